@@ -1,6 +1,8 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
-use crate::uds_proto::AsiClient;
+use crate::uds_proto::{AsiClient, CacheStats, ModuleInfo, ModuleSource, ProcessInfo, ProcessState, RunOptions};
 
 pub mod uds_proto;
 
@@ -18,12 +20,127 @@ enum AsiCommands {
 
     /// Start a process in an a-Si fabric.
     Run {
+        /// Run a module uploaded to the host, `<name>` for its latest version or
+        /// `<name>@<version>`, instead of the local build.
+        #[arg(long)]
+        module: Option<String>,
+
+        /// Process name, the registered name or the module's name section by default.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// JSON network policy file to apply to the process.
+        #[arg(long)]
+        net_policy: Option<PathBuf>,
+
+        /// Where the process's output goes, `buffer` (see `logs`) or `file:<path>`,
+        /// writing `<path>.<pid>.stdout` and `<path>.<pid>.stderr` in the host's
+        /// output directory.
+        #[arg(long)]
+        output: Option<String>,
+
+        /// Maximum size of the process's linear memory, in bytes.
+        #[arg(long)]
+        memory_limit: Option<u64>,
+
+        /// Fuel the process may consume, roughly one unit per wasm instruction.
+        #[arg(long)]
+        fuel: Option<u64>,
+
+        /// Wall-clock time the process may run for, in milliseconds.
+        #[arg(long)]
+        time_limit: Option<u64>,
+
+        /// Wait for the process to terminate and exit with its exit code, or
+        /// with 134 if it trapped, 137 if it was killed and 125 if it went over
+        /// a resource limit.
+        #[arg(long)]
+        wait: bool,
+    },
+
+    /// List the processes of the host.
+    Ps,
+
+    /// Kill a running process.
+    Kill {
+        /// Process id.
+        pid: u32,
+    },
+
+    /// Manage the modules uploaded to the host.
+    Modules {
+        #[command(subcommand)]
+        command: ModuleCommands,
+    },
+
+    /// Manage the host's compiled module cache.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    /// Stream the output of a running process until it exits.
+    Attach {
+        /// Process id.
+        pid: u32,
+    },
+
+    /// Change the log filter of a running process.
+    LogLevel {
+        /// Process id.
+        pid: u32,
+
+        /// Log filter directives, e.g. `info,net=debug`.
+        filter: String,
+    },
+
+    /// Show the captured log records and output of a process.
+    Logs {
+        /// Process id.
+        pid: u32,
+
+        /// Keep streaming new entries until the process exits.
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Log filter directives for records, e.g. `warn` or `info,net=debug`.
+        #[arg(long)]
+        level: Option<String>,
     },
 
     /// Shutdown the a-Si host.
     Shutdown,
 }
 
+#[derive(Subcommand)]
+enum ModuleCommands {
+    /// Upload a module, as the next version of its name.
+    Upload {
+        /// Module name.
+        name: String,
+
+        /// Path of the wasm binary.
+        path: PathBuf,
+    },
+
+    /// List the uploaded modules and their versions.
+    List,
+
+    /// Remove a module, every version of `<name>` or only `<name>@<version>`.
+    Rm {
+        module: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Show cache statistics.
+    Stats,
+
+    /// Drop every compiled module, they are compiled again on their next run.
+    Clear,
+}
+
 fn main() {
     let args = Cli::parse();
 
@@ -43,16 +160,137 @@ fn main() {
             }
         },
 
-        AsiCommands::Run {  } => {
+        AsiCommands::Run { module, name, net_policy, output, memory_limit, fuel, time_limit, wait } => {
+            // The local build runs unless an uploaded module is named.
             let path = "../target/wasm32-wasi/release/userland.wasm";
-            let wasm_bin = std::fs::read(path).expect("failed to load wasm");
+            let wasm_bin = match &module {
+                Some(_) => vec![],
+                None => std::fs::read(path).expect("failed to load wasm"),
+            };
+            let (source, label) = match &module {
+                Some(module) => (ModuleSource::Registered(module), module.as_str()),
+                None => (ModuleSource::Binary(&wasm_bin), path),
+            };
+
+            let net_policy = match net_policy.map(std::fs::read).transpose() {
+                Ok(policy) => policy,
+                Err(err) => {
+                    eprintln!("Failed to read network policy: {}", err);
+                    return;
+                },
+            };
+
+            // Limits not given are left to the host.
+            let limits = if memory_limit.is_some() || fuel.is_some() || time_limit.is_some() {
+                let limits = serde_json::json!({
+                    "memory_bytes": memory_limit,
+                    "fuel": fuel,
+                    "wall_time_ms": time_limit,
+                });
+                Some(limits.to_string().into_bytes())
+            } else {
+                None
+            };
 
-            match client.run(&wasm_bin) {
-                Ok(_) => println!("Started '{}'", path),
+            let options = RunOptions {
+                name: name.as_deref(),
+                net_policy: net_policy.as_deref(),
+                output: output.as_deref(),
+                limits: limits.as_deref(),
+            };
+
+            if wait {
+                let started = |pid| println!("Started '{}' as process {}", label, pid);
+                match client.run_wait(source, options, started) {
+                    Ok(state) => std::process::exit(report_termination(&state)),
+                    Err(err) => eprintln!("Error: {}", err),
+                }
+                return;
+            }
+
+            match client.run(source, options) {
+                Ok(pid) => println!("Started '{}' as process {}", label, pid),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Ps => {
+            match client.list() {
+                Ok(processes) => print_processes(&processes),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Kill { pid } => {
+            match client.kill(pid) {
+                Ok(()) => println!("Process {} killed", pid),
                 Err(err) => eprintln!("Error: {}", err),
             }
         },
 
+        AsiCommands::Modules { command: ModuleCommands::Upload { name, path } } => {
+            let wasm_bin = match std::fs::read(&path) {
+                Ok(wasm_bin) => wasm_bin,
+                Err(err) => {
+                    eprintln!("Failed to read module: {}", err);
+                    return;
+                },
+            };
+
+            match client.upload(&name, &wasm_bin) {
+                Ok(version) => println!("Uploaded '{}' as {}@{}", path.to_string_lossy(), name, version),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Modules { command: ModuleCommands::List } => {
+            match client.list_modules() {
+                Ok(modules) => print_modules(&modules),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Modules { command: ModuleCommands::Rm { module } } => {
+            match client.remove_module(&module) {
+                Ok(1) => println!("Removed '{}'", module),
+                Ok(removed) => println!("Removed {} versions of '{}'", removed, module),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Cache { command: CacheCommands::Stats } => {
+            match client.cache_stats() {
+                Ok(stats) => print_cache_stats(&stats),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Cache { command: CacheCommands::Clear } => {
+            match client.clear_cache() {
+                Ok(()) => println!("Module cache cleared"),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Attach { pid } => {
+            if let Err(err) = client.attach(pid, &mut std::io::stdout(), &mut std::io::stderr()) {
+                eprintln!("Error: {}", err);
+            }
+        },
+
+        AsiCommands::LogLevel { pid, filter } => {
+            match client.set_log_filter(pid, &filter) {
+                Ok(()) => println!("Process {} log filter set to '{}'", pid, filter),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        },
+
+        AsiCommands::Logs { pid, follow, level } => {
+            if let Err(err) = client.logs(pid, follow, level.as_deref(), &mut std::io::stdout()) {
+                eprintln!("Error: {}", err);
+            }
+        },
+
         AsiCommands::Shutdown => {
             match client.shutdown() {
                 Ok(_) => println!("Host is shutting down"),
@@ -61,3 +299,75 @@ fn main() {
         },
     }
 }
+
+/// Print how a process terminated, returning the exit code to exit with.
+fn report_termination(state: &ProcessState) -> i32 {
+    match state {
+        ProcessState::Exited { code } => {
+            println!("Process exited with code {}", code);
+            *code
+        },
+        ProcessState::Trapped { message, trap_code, backtrace } => {
+            match trap_code {
+                Some(trap_code) => eprintln!("Process trapped ({}): {}", trap_code, message),
+                None => eprintln!("Process failed: {}", message),
+            }
+            for (index, frame) in backtrace.iter().enumerate() {
+                let func = match &frame.func_name {
+                    Some(name) => name.clone(),
+                    None => format!("<func {}>", frame.func_index),
+                };
+                let offset = frame.module_offset.map(|offset| format!(" @ {:#x}", offset)).unwrap_or_default();
+                eprintln!("{:>4}: {}!{}{}", index, frame.module.as_deref().unwrap_or("<module>"), func, offset);
+            }
+            134
+        },
+        ProcessState::Killed => {
+            eprintln!("Process was killed");
+            137
+        },
+        ProcessState::LimitExceeded { limit } => {
+            eprintln!("Process went over its {} limit", limit.replace('_', " "));
+            125
+        },
+        ProcessState::Starting | ProcessState::Running => {
+            eprintln!("Process has not terminated");
+            1
+        },
+    }
+}
+
+fn print_processes(processes: &[ProcessInfo]) {
+    println!("{:>6}  {:<20} {:<16} {:>10} {:>10} {:>8}", "PID", "NAME", "STATE", "TIME", "MEMORY", "RPCS");
+    for process in processes {
+        let state = match &process.state {
+            ProcessState::Starting => "starting".to_string(),
+            ProcessState::Running => "running".to_string(),
+            ProcessState::Exited { code } => format!("exited({})", code),
+            ProcessState::Trapped { .. } => "trapped".to_string(),
+            ProcessState::Killed => "killed".to_string(),
+            ProcessState::LimitExceeded { limit } => format!("limit({})", limit),
+        };
+        let time = format!("{:.1}s", process.run_time_ms as f64 / 1000.0);
+        let memory = format!("{}K", process.memory_bytes / 1024);
+
+        println!("{:>6}  {:<20} {:<16} {:>10} {:>10} {:>8}", process.pid, process.name, state, time, memory, process.rpc_calls);
+        if let ProcessState::Trapped { message, .. } = &process.state {
+            println!("{:>8}{}", "", message);
+        }
+    }
+}
+
+fn print_modules(modules: &[ModuleInfo]) {
+    println!("{:<24} {:>8} {:>10}", "NAME", "VERSION", "SIZE");
+    for module in modules {
+        println!("{:<24} {:>8} {:>10}", module.name, module.version, format!("{}K", module.size_bytes / 1024));
+    }
+}
+
+fn print_cache_stats(stats: &CacheStats) {
+    println!("Modules in memory: {}", stats.memory_entries);
+    println!("Modules on disk:   {} ({}K)", stats.disk_entries, stats.disk_bytes / 1024);
+    println!("Hits:              {} in memory, {} on disk", stats.memory_hits, stats.disk_hits);
+    println!("Compiled:          {}", stats.misses);
+}
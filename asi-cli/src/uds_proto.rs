@@ -1,7 +1,8 @@
 
-use std::{io::{Write, Read}, path::Path, vec};
+use std::{borrow::Cow, io::{Write, Read}, path::Path, vec};
 
 use byteorder::{WriteBytesExt, LittleEndian, ReadBytesExt};
+use serde::Deserialize;
 
 #[cfg(windows)]
 use uds_windows::UnixStream;
@@ -24,7 +25,35 @@ pub enum ClientRequest<'a> {
     ServerVersion,
     Shutdown,
     Run {
-        binary_data: &'a [u8]
+        module: ModuleSource<'a>,
+        options: RunOptions<'a>,
+        wait: bool,
+    },
+    List,
+    Kill {
+        pid: u32,
+    },
+    Upload {
+        name: &'a str,
+        binary_data: &'a [u8],
+    },
+    ListModules,
+    RemoveModule {
+        module: &'a str,
+    },
+    CacheStats,
+    ClearCache,
+    SetLogFilter {
+        pid: u32,
+        filter: &'a str,
+    },
+    Attach {
+        pid: u32,
+    },
+    Logs {
+        pid: u32,
+        follow: bool,
+        filter: Option<&'a str>,
     },
 }
 
@@ -34,17 +63,146 @@ impl <'a> ClientRequest<'a> {
             ClientRequest::ServerVersion => 0,
             ClientRequest::Shutdown => 1,
             ClientRequest::Run {..} => 2,
+            ClientRequest::SetLogFilter {..} => 3,
+            ClientRequest::Logs {..} => 4,
+            ClientRequest::Attach {..} => 5,
+            ClientRequest::List => 6,
+            ClientRequest::Kill {..} => 7,
+            ClientRequest::CacheStats => 8,
+            ClientRequest::ClearCache => 9,
+            ClientRequest::Upload {..} => 10,
+            ClientRequest::ListModules => 11,
+            ClientRequest::RemoveModule {..} => 12,
         }
     }
 
-    fn payloads(&self) -> Vec<&[u8]> {
-        match self {
-            ClientRequest::Run { binary_data } => vec![binary_data],
+    fn payloads(&self) -> Vec<Cow<'a, [u8]>> {
+        match *self {
+            ClientRequest::Run { module, options, wait } => {
+                // Optional payloads are skipped by leaving them empty, trailing ones are left out.
+                let flags = if wait { vec![1u8] } else { vec![] };
+                let (binary_data, registered) = match module {
+                    ModuleSource::Binary(binary_data) => (binary_data, ""),
+                    ModuleSource::Registered(module) => (&[][..], module),
+                };
+                let mut payloads: Vec<Cow<[u8]>> = vec![
+                    binary_data.into(),
+                    options.net_policy.unwrap_or_default().into(),
+                    options.output.unwrap_or_default().as_bytes().into(),
+                    options.name.unwrap_or_default().as_bytes().into(),
+                    flags.into(),
+                    options.limits.unwrap_or_default().into(),
+                    registered.as_bytes().into(),
+                ];
+                while payloads.len() > 1 && matches!(payloads.last(), Some(payload) if payload.is_empty()) {
+                    payloads.pop();
+                }
+                payloads
+            },
+            ClientRequest::SetLogFilter { pid, filter } => vec![pid.to_le_bytes().to_vec().into(), filter.as_bytes().into()],
+            ClientRequest::Upload { name, binary_data } => vec![name.as_bytes().into(), binary_data.into()],
+            ClientRequest::RemoveModule { module } => vec![module.as_bytes().into()],
+            ClientRequest::Attach { pid } | ClientRequest::Kill { pid } => vec![pid.to_le_bytes().to_vec().into()],
+            ClientRequest::Logs { pid, follow, filter } => {
+                let mut payloads: Vec<Cow<[u8]>> = vec![pid.to_le_bytes().to_vec().into(), vec![follow as u8].into()];
+                payloads.extend(filter.map(|filter| filter.as_bytes().into()));
+                payloads
+            },
             _ => vec![],
         }
     }
 }
 
+/// Module a process runs.
+#[derive(Debug, Clone, Copy)]
+pub enum ModuleSource<'a> {
+    /// Module binary, sent with the request.
+    Binary(&'a [u8]),
+    /// Module uploaded to the host, `<name>` for its latest version or `<name>@<version>`.
+    Registered(&'a str),
+}
+
+/// Optional settings of a process to run, the host's defaults where not set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunOptions<'a> {
+    /// Process name, the module's name section by default.
+    pub name: Option<&'a str>,
+    /// JSON network policy.
+    pub net_policy: Option<&'a [u8]>,
+    /// Output route, `buffer` or `file:<host path>`.
+    pub output: Option<&'a str>,
+    /// JSON resource limits.
+    pub limits: Option<&'a [u8]>,
+}
+
+/// Lifecycle state of a process.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ProcessState {
+    Starting,
+    Running,
+    Exited {
+        code: i32,
+    },
+    Trapped {
+        message: String,
+        trap_code: Option<String>,
+        /// Guest stack at the trap, innermost frame first.
+        backtrace: Vec<TrapFrame>,
+    },
+    Killed,
+    LimitExceeded {
+        /// Limit exceeded: `memory`, `table`, `fuel` or `wall_time`.
+        limit: String,
+    },
+}
+
+/// Guest function on the stack of a trap.
+#[derive(Deserialize, Debug)]
+pub struct TrapFrame {
+    pub module: Option<String>,
+    pub func_index: u32,
+    pub func_name: Option<String>,
+    pub module_offset: Option<usize>,
+}
+
+/// Entry of the host's process table.
+#[derive(Deserialize, Debug)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ProcessState,
+    /// Spawn time, in milliseconds since the Unix epoch.
+    pub started_ms: u64,
+    /// Time since spawn, or until termination.
+    pub run_time_ms: u64,
+    pub rpc_calls: u64,
+    pub memory_bytes: u64,
+}
+
+/// Module version uploaded to the host.
+#[derive(Deserialize, Debug)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub version: u32,
+    pub size_bytes: u64,
+    /// Upload time, in milliseconds since the Unix epoch.
+    pub uploaded_ms: u64,
+}
+
+/// Statistics of the host's compiled module cache.
+#[derive(Deserialize, Debug)]
+pub struct CacheStats {
+    pub memory_entries: usize,
+    pub disk_entries: usize,
+    pub disk_bytes: u64,
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    /// Lookups that compiled the module.
+    pub misses: u64,
+}
+
 pub struct AsiClient {
     stream: UnixStream,
 }
@@ -57,6 +215,15 @@ impl AsiClient {
     }
 
     fn send_frame(&mut self, request: ClientRequest) -> Result<Vec<u8>, Error> {
+        self.send_request(request)?;
+
+        let mut payload = vec![];
+        self.stream.read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Send a request, leaving the body of a successful response to be read from the stream.
+    fn send_request(&mut self, request: ClientRequest) -> Result<(), Error> {
         let payloads = request.payloads();
         if payloads.len() > u8::MAX as usize {
             panic!();
@@ -69,17 +236,12 @@ impl AsiClient {
 
         for payload in payloads {
             self.stream.write_u64::<LittleEndian>(payload.len() as u64)?;
-            self.stream.write_all(payload)?;
+            self.stream.write_all(&payload)?;
         }
 
         let response = self.stream.read_u8()?;
         match response {
-            0 => {
-
-                let mut payload = vec![];
-                self.stream.read_to_end(&mut payload)?;
-                Ok(payload)
-            },
+            0 => Ok(()),
             1 => {
                 let mut message = vec![];
                 self.stream.read_to_end(&mut message)?;
@@ -102,12 +264,150 @@ impl AsiClient {
         Ok(())
     }
 
-    pub fn run(mut self, binary_data: &[u8]) -> Result<(), Error> {
+    /// Start a process, returning its process id.
+    pub fn run(mut self, module: ModuleSource, options: RunOptions) -> Result<u32, Error> {
+        let request = ClientRequest::Run {
+            module,
+            options,
+            wait: false,
+        };
+        let pid = self.send_frame(request)?;
+
+        Self::decode_pid(&pid)
+    }
+
+    /// Start a process and wait for it to terminate, returning its final state.
+    ///
+    /// `started` is called with the process id once the process is running.
+    pub fn run_wait(mut self, module: ModuleSource, options: RunOptions, started: impl FnOnce(u32)) -> Result<ProcessState, Error> {
         let request = ClientRequest::Run {
-            binary_data
+            module,
+            options,
+            wait: true,
         };
-        self.send_frame(request)?;
+        self.send_request(request)?;
+
+        let mut pid = [0u8; 4];
+        self.stream.read_exact(&mut pid)?;
+        started(u32::from_le_bytes(pid));
+
+        let mut state = vec![];
+        self.stream.read_to_end(&mut state)?;
+        if state.is_empty() {
+            return Err(Error::ProtocolError("host stopped waiting before the process terminated".to_string()));
+        }
+        serde_json::from_slice(&state).map_err(|err| Error::ProtocolError(format!("bad process state: {}", err)))
+    }
+
+    /// List the host's processes.
+    pub fn list(mut self) -> Result<Vec<ProcessInfo>, Error> {
+        let list = self.send_frame(ClientRequest::List)?;
+
+        serde_json::from_slice(&list).map_err(|err| Error::ProtocolError(format!("bad process list: {}", err)))
+    }
+
+    /// Kill a running process.
+    pub fn kill(mut self, pid: u32) -> Result<(), Error> {
+        self.send_frame(ClientRequest::Kill { pid })?;
 
         Ok(())
     }
+
+    /// Upload a module as the next version of `name`, returning the version.
+    pub fn upload(mut self, name: &str, binary_data: &[u8]) -> Result<u32, Error> {
+        let version = self.send_frame(ClientRequest::Upload { name, binary_data })?;
+
+        match version.try_into() {
+            Ok(version) => Ok(u32::from_le_bytes(version)),
+            Err(_) => Err(Error::ProtocolError("bad module version".to_string())),
+        }
+    }
+
+    /// List the modules uploaded to the host.
+    pub fn list_modules(mut self) -> Result<Vec<ModuleInfo>, Error> {
+        let list = self.send_frame(ClientRequest::ListModules)?;
+
+        serde_json::from_slice(&list).map_err(|err| Error::ProtocolError(format!("bad module list: {}", err)))
+    }
+
+    /// Remove an uploaded module, `<name>` for every version or `<name>@<version>`,
+    /// returning the number of versions removed.
+    pub fn remove_module(mut self, module: &str) -> Result<u32, Error> {
+        let removed = self.send_frame(ClientRequest::RemoveModule { module })?;
+
+        match removed.try_into() {
+            Ok(removed) => Ok(u32::from_le_bytes(removed)),
+            Err(_) => Err(Error::ProtocolError("bad removed version count".to_string())),
+        }
+    }
+
+    /// Statistics of the host's compiled module cache.
+    pub fn cache_stats(mut self) -> Result<CacheStats, Error> {
+        let stats = self.send_frame(ClientRequest::CacheStats)?;
+
+        serde_json::from_slice(&stats).map_err(|err| Error::ProtocolError(format!("bad cache stats: {}", err)))
+    }
+
+    /// Drop every compiled module from the host's cache.
+    pub fn clear_cache(mut self) -> Result<(), Error> {
+        self.send_frame(ClientRequest::ClearCache)?;
+
+        Ok(())
+    }
+
+    /// Change the log filter of a running process.
+    pub fn set_log_filter(mut self, pid: u32, filter: &str) -> Result<(), Error> {
+        self.send_frame(ClientRequest::SetLogFilter { pid, filter })?;
+
+        Ok(())
+    }
+
+    /// Copy the captured log of a process to `out`, and its new entries
+    /// until it exits if `follow`ing.
+    pub fn logs(mut self, pid: u32, follow: bool, filter: Option<&str>, out: &mut impl Write) -> Result<(), Error> {
+        self.send_request(ClientRequest::Logs { pid, follow, filter })?;
+
+        // Copy in chunks as they arrive so followed output is not held back.
+        let mut buffer = [0u8; 4096];
+        loop {
+            let len = self.stream.read(&mut buffer)?;
+            if len == 0 {
+                return Ok(());
+            }
+            out.write_all(&buffer[..len])?;
+            out.flush()?;
+        }
+    }
+
+    /// Copy the output of a running process to `stdout` and `stderr` until it exits.
+    pub fn attach(mut self, pid: u32, stdout: &mut impl Write, stderr: &mut impl Write) -> Result<(), Error> {
+        self.send_request(ClientRequest::Attach { pid })?;
+
+        loop {
+            // Output arrives as frames of the stream, the length and the bytes.
+            let stream = match self.stream.read_u8() {
+                Ok(stream) => stream,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            let len = self.stream.read_u32::<LittleEndian>()?;
+            let mut bytes = vec![0u8; len as usize];
+            self.stream.read_exact(&mut bytes)?;
+
+            let out: &mut dyn Write = match stream {
+                1 => stdout,
+                2 => stderr,
+                _ => return Err(Error::ProtocolError("bad output stream".to_string())),
+            };
+            out.write_all(&bytes)?;
+            out.flush()?;
+        }
+    }
+
+    fn decode_pid(payload: &[u8]) -> Result<u32, Error> {
+        match payload.try_into() {
+            Ok(pid) => Ok(u32::from_le_bytes(pid)),
+            Err(_) => Err(Error::ProtocolError("bad process id".to_string())),
+        }
+    }
 }
\ No newline at end of file
@@ -1,77 +1,137 @@
-use std::{cmp::min, mem::size_of, net::ToSocketAddrs};
+use std::{cmp::min, collections::{HashMap, VecDeque}, sync::{Arc, Mutex, atomic::AtomicU64}};
 
-use libasi_interop::{AsiRpcError, RpcRequest, diagnostics::{HelloRpcRequest, PokeRpcRequest, LogRpcRequest}, net::{ConnectRpcRequest, LookupRpcRequest, NetError, ConnectAddrs}};
-use serde::Serialize;
+use libasi_interop::{AsiRpcError, RpcRequest, HandshakeRpcRequest, HandshakeResponse, RPC_PROTOCOL_VERSION, encoding::{RpcEncoding, RPC_HEADER_LEN, RPC_FLAG_PIPELINED, RPC_REQUEST_ID_LEN, decode_header, encode_frame_header}};
+use tokio::{sync::mpsc, task::JoinHandle};
 use wasi_common::{WasiFile, file::FileType, Error, snapshots::preview_1::types::Errno};
-pub struct AsiSysreqDevice {
-    pending_response: Vec<u8>,
-    count: u64,
+
+use crate::{fd_handoff::FdHandoff, net_policy::NetPolicy, process::Process, resolver::Resolver};
+
+use self::dispatch::serialize_result;
+pub use self::dispatch::{RpcDispatchTable, DispatchError};
+
+mod diagnostics;
+mod dispatch;
+mod events;
+mod net;
+
+/// Per-process state available to RPC handlers.
+///
+/// Handlers of pipelined requests run concurrently, they share the context.
+pub struct SysreqContext {
+    dispatch: Arc<RpcDispatchTable>,
+    handoff: FdHandoff,
+    net_policy: NetPolicy,
+    resolver: Arc<Resolver>,
+    /// The process making the requests.
+    process: Arc<Process>,
+    /// Tasks of the guest's running timers.
+    timers: Mutex<HashMap<u64, JoinHandle<()>>>,
+    poke_count: AtomicU64,
 }
 
-impl AsiSysreqDevice {
-    pub fn new() -> Self {
+impl SysreqContext {
+    pub fn new(dispatch: Arc<RpcDispatchTable>, handoff: FdHandoff, net_policy: NetPolicy, resolver: Arc<Resolver>, process: Arc<Process>) -> Self {
         Self {
-            pending_response: Vec::new(),
-            count: 0,
+            dispatch,
+            handoff,
+            net_policy,
+            resolver,
+            process,
+            timers: Mutex::new(HashMap::new()),
+            poke_count: AtomicU64::new(0),
         }
     }
+}
 
-    fn hello(&mut self, hello: HelloRpcRequest) -> Result<<HelloRpcRequest as RpcRequest>::Response, AsiRpcError> {
-        log::info!("SYSREQ Hello {}", hello.who);
-        Ok(())
+impl Drop for SysreqContext {
+    fn drop(&mut self) {
+        // Timers do not outlive the process.
+        for task in self.timers.get_mut().expect("timers lock poisoned").values() {
+            task.abort();
+        }
     }
-    
-    fn log(&mut self, record: LogRpcRequest) -> Result<<HelloRpcRequest as RpcRequest>::Response, AsiRpcError> {
-        let level = match record.level {
-            1 => log::Level::Error,
-            2 => log::Level::Warn,
-            3 => log::Level::Info,
-            4 => log::Level::Debug,
-            _ => log::Level::Trace,
-        };
-        let target = format!("GUEST:{}", record.target);
+}
 
-        let logger = log::logger();
-        logger.log(&log::Record::builder()
-            .level(level)
-            .target(&target)
-            .module_path(record.file.as_ref().map(|s| s.as_str()))
-            .file(record.file.as_ref().map(|s| s.as_str()))
-            .line(record.line)
-            .args(format_args!("{}", record.body))
-            .build()
-        );
+/// Build the dispatch table with the handlers of every subsystem.
+pub fn standard_dispatch_table() -> Result<RpcDispatchTable, DispatchError> {
+    let mut table = RpcDispatchTable::new();
+    table.register(handshake)?;
+    diagnostics::register(&mut table)?;
+    events::register(&mut table)?;
+    net::register(&mut table)?;
+    Ok(table)
+}
 
-        Ok(())
+fn handshake(ctx: &SysreqContext, handshake: HandshakeRpcRequest) -> Result<<HandshakeRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    if handshake.version != RPC_PROTOCOL_VERSION {
+        log::info!("Guest speaks RPC protocol version {}, host speaks {}", handshake.version, RPC_PROTOCOL_VERSION);
     }
 
-    fn poke(&mut self, _poke: PokeRpcRequest) -> Result<<PokeRpcRequest as RpcRequest>::Response, AsiRpcError> {
-        self.count += 1;
-        Ok(self.count)
-    }
+    Ok(HandshakeResponse {
+        version: RPC_PROTOCOL_VERSION,
+        op_codes: ctx.dispatch.op_codes(),
+        encodings: vec![RpcEncoding::Json.to_flags(), RpcEncoding::Postcard.to_flags()],
+    })
+}
+
+pub struct AsiSysreqDevice {
+    ctx: Arc<SysreqContext>,
+    /// Response to the outstanding unpipelined request.
+    pending_response: Vec<u8>,
+    /// Framed responses to pipelined requests, in completion order.
+    completed: VecDeque<Vec<u8>>,
+    /// Pipelined requests run as tasks, which send their framed response here.
+    frame_sender: mpsc::UnboundedSender<Vec<u8>>,
+    frame_receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Tasks of pipelined requests whose response was not received yet.
+    running: Vec<JoinHandle<()>>,
+    /// Pipelined requests whose response was not received yet.
+    outstanding: usize,
+}
 
-    fn net_connect(&mut self, connect: ConnectRpcRequest) -> Result<<ConnectRpcRequest as RpcRequest>::Response, AsiRpcError> {
-        match connect.target {
-            ConnectAddrs::Tcp { addrs: _ } => Err(AsiRpcError::BadRequest),
+impl AsiSysreqDevice {
+    /// Maximum number of pipelined requests running or waiting for the guest
+    /// to read their response.
+    const MAX_COMPLETED: usize = 256;
+
+    pub fn new(ctx: SysreqContext) -> Self {
+        let (frame_sender, frame_receiver) = mpsc::unbounded_channel();
+        Self {
+            ctx: Arc::new(ctx),
+            pending_response: Vec::new(),
+            completed: VecDeque::new(),
+            frame_sender,
+            frame_receiver,
+            running: Vec::new(),
+            outstanding: 0,
         }
     }
 
-    fn net_lookup(&mut self, lookup: LookupRpcRequest) -> Result<<LookupRpcRequest as RpcRequest>::Response, AsiRpcError> {
-        match lookup.query.to_socket_addrs() {
-            Ok(addrs) => Ok(Ok(addrs.collect())),
-            Err(_) => Ok(Err(NetError::Failed)),
+    /// Queue the responses of the pipelined requests that completed, waiting
+    /// for one if `wait` is set and no response is ready but some are expected.
+    async fn receive_frames(&mut self, wait: bool) {
+        if wait && self.pending_response.is_empty() && self.completed.is_empty() && self.outstanding > 0 {
+            // The device holds a sender, the channel never closes.
+            if let Some(frame) = self.frame_receiver.recv().await {
+                self.outstanding -= 1;
+                self.completed.push_back(frame);
+            }
         }
-    }
 
-    fn deserialize_request<T: RpcRequest> (buffer: &[u8]) -> Result<T, AsiRpcError> {
-        match serde_json::from_slice(&buffer) {
-            Ok(request) => Ok(request),
-            Err(_) => Err(AsiRpcError::BadRequest),
+        while let Ok(frame) = self.frame_receiver.try_recv() {
+            self.outstanding -= 1;
+            self.completed.push_back(frame);
         }
+        self.running.retain(|task| !task.is_finished());
     }
+}
 
-    fn serialize_result<T: Serialize> (result: Result<T, AsiRpcError>) -> Vec<u8> {
-        serde_json::to_vec(&result).unwrap_or_else(|_| vec![])
+impl Drop for AsiSysreqDevice {
+    fn drop(&mut self) {
+        // Pipelined requests do not outlive the process.
+        for task in &self.running {
+            task.abort();
+        }
     }
 }
 
@@ -91,56 +151,194 @@ impl WasiFile for AsiSysreqDevice {
             return Err(Errno::Inprogress.into())
         }
 
-        let opcode = if bufs.len() == 1 && bufs[0].len() >= size_of::<u32>() {
-            u32::from_le_bytes(bufs[0][0..size_of::<u32>()].try_into().expect("slice length of 4"))
+        // Gather the request, avoiding a copy in the common single buffer case.
+        let gathered: Vec<u8>;
+        let request = if bufs.len() == 1 {
+            &bufs[0][..]
         } else {
-            // No support yet for vectored writes, these should never happen at the moment.
+            gathered = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            &gathered[..]
+        };
+
+        if request.len() < RPC_HEADER_LEN {
             return Err(Errno::Inval.into())
+        }
+        let (opcode, flags) = decode_header(request[..RPC_HEADER_LEN].try_into().expect("slice length of header"));
+        let mut request_buf = &request[RPC_HEADER_LEN..];
+
+        let request_id = if flags & RPC_FLAG_PIPELINED != 0 {
+            if request_buf.len() < RPC_REQUEST_ID_LEN {
+                return Err(Errno::Inval.into())
+            }
+            self.receive_frames(false).await;
+            if self.completed.len() + self.outstanding >= Self::MAX_COMPLETED {
+                // Guest must collect responses before issuing more requests.
+                return Err(Errno::Again.into())
+            }
+            let request_id = u32::from_le_bytes(request_buf[..RPC_REQUEST_ID_LEN].try_into().expect("slice length of 4"));
+            request_buf = &request_buf[RPC_REQUEST_ID_LEN..];
+            Some(request_id)
+        } else {
+            if !self.completed.is_empty() || self.outstanding > 0 {
+                // Unpipelined requests cannot be mixed with pending pipelined responses.
+                return Err(Errno::Inprogress.into())
+            }
+            None
         };
 
-        let request_buf = &bufs[0][size_of::<u32>()..];
-        
-        let resp = match opcode {
-            HelloRpcRequest::OP_CODE => {
-                Self::serialize_result(
-                    Self::deserialize_request(request_buf).and_then(|req| self.hello(req)))
-            },
-            PokeRpcRequest::OP_CODE => {
-                Self::serialize_result(
-                    Self::deserialize_request(request_buf).and_then(|req| self.poke(req)))
-            },
-            LogRpcRequest::OP_CODE => {
-                Self::serialize_result(
-                    Self::deserialize_request(request_buf).and_then(|req| self.log(req)))
-            },
-            ConnectRpcRequest::OP_CODE => {
-                Self::serialize_result(
-                    Self::deserialize_request(request_buf).and_then(|req| self.net_connect(req)))
-            },
-            LookupRpcRequest::OP_CODE => {
-                Self::serialize_result(
-                    Self::deserialize_request(request_buf).and_then(|req| self.net_lookup(req)))
-            },
-            _ => {
-                Self::serialize_result(Err::<(), _>(AsiRpcError::BadRequest))
+        self.ctx.process.stats.count_rpc_call();
+
+        let Some(encoding) = RpcEncoding::from_flags(flags) else {
+            // The guest asked for an encoding the host does not know, reply in the debug encoding.
+            let resp = serialize_result(RpcEncoding::Json, Err::<(), _>(AsiRpcError::BadRequest));
+            match request_id {
+                Some(request_id) => {
+                    self.completed.push_back(frame(request_id, resp));
+                },
+                None => {
+                    self.pending_response = resp;
+                },
             }
+            return Ok(request.len() as u64);
         };
-        
-        self.pending_response = resp;
 
-        Ok(bufs[0].len() as u64)
+        match request_id {
+            Some(request_id) => {
+                // Pipelined requests run concurrently, their responses are queued as they complete.
+                let ctx = self.ctx.clone();
+                let request_buf = request_buf.to_vec();
+                let frame_sender = self.frame_sender.clone();
+                self.outstanding += 1;
+                self.running.push(tokio::spawn(async move {
+                    let resp = ctx.dispatch.dispatch(&ctx, opcode, encoding, &request_buf).await;
+                    let _ = frame_sender.send(frame(request_id, resp));
+                }));
+            },
+            None => {
+                self.pending_response = self.ctx.dispatch.dispatch(&self.ctx, opcode, encoding, request_buf).await;
+            },
+        }
+
+        Ok(request.len() as u64)
     }
 
     async fn read_vectored<'a> (&mut self, bufs: &mut [std::io::IoSliceMut<'a>]) -> Result<u64, Error> {
-        if bufs.len() != 1 {
-            // No support yet for vectored writes, these should never happen at the moment.
-            return Err(Errno::Inval.into());
+        // A guest reading while its pipelined requests run waits for the next response.
+        self.receive_frames(true).await;
+
+        // Scatter as much of the pending response, or of the queued frames, as fits across the guest's buffers.
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let source = if !self.pending_response.is_empty() {
+                    &mut self.pending_response
+                } else if let Some(frame) = self.completed.front_mut() {
+                    frame
+                } else {
+                    break;
+                };
+
+                let sz = min(buf.len() - filled, source.len());
+                buf[filled..filled + sz].copy_from_slice(&source[..sz]);
+                source.drain(..sz);
+                filled += sz;
+
+                if self.completed.front().map_or(false, |frame| frame.is_empty()) {
+                    self.completed.pop_front();
+                }
+            }
+            read += filled;
         }
 
-        let sz = min(bufs[0].len(), self.pending_response.len());
-        bufs[0][0..sz].copy_from_slice(&self.pending_response[0..sz]);
-        self.pending_response.drain(0..sz);
+        Ok(read as u64)
+    }
+}
+
+/// Frame `resp` as the response to pipelined request `request_id`.
+fn frame(request_id: u32, resp: Vec<u8>) -> Vec<u8> {
+    let mut frame = encode_frame_header(request_id, resp.len() as u32).to_vec();
+    frame.extend_from_slice(&resp);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{IoSlice, IoSliceMut}, time::Duration};
+
+    use libasi_interop::{diagnostics::LogFilter, encoding::{RPC_FRAME_HEADER_LEN, decode_frame_header, encode_header}};
+    use serde::{Serialize, Deserialize};
+
+    use crate::{host_events::HostEventSender, process_log::ProcessLog, resolver::Resolver};
+
+    use super::{*, dispatch::RpcFuture};
+
+    /// Responds with its delay once it has passed.
+    #[derive(Serialize, Deserialize, Debug)]
+    struct SleepRpcRequest {
+        delay_ms: u64,
+    }
+
+    impl RpcRequest for SleepRpcRequest {
+        type Response = u64;
+        const OP_CODE: u32 = 90001;
+    }
+
+    fn sleep(_ctx: &SysreqContext, sleep: SleepRpcRequest) -> RpcFuture<'_, SleepRpcRequest> {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(sleep.delay_ms)).await;
+            Ok(sleep.delay_ms)
+        })
+    }
+
+    fn device() -> AsiSysreqDevice {
+        let mut table = RpcDispatchTable::new();
+        table.register_async(sleep).unwrap();
+
+        let (events, _) = HostEventSender::channel();
+        let process = Arc::new(Process::new(1, "test".to_string(), events, LogFilter::new(None), ProcessLog::new()));
+        let resolver = Arc::new(Resolver::Static(HashMap::new()));
+        AsiSysreqDevice::new(SysreqContext::new(Arc::new(table), FdHandoff::new(), NetPolicy::allow_all(), resolver, process))
+    }
+
+    async fn submit(device: &mut AsiSysreqDevice, request_id: u32, delay_ms: u64) {
+        let mut request = encode_header(SleepRpcRequest::OP_CODE, RpcEncoding::Postcard.to_flags() | RPC_FLAG_PIPELINED).to_vec();
+        request.extend_from_slice(&request_id.to_le_bytes());
+        RpcEncoding::Postcard.encode_into(&SleepRpcRequest { delay_ms }, &mut request).unwrap();
+        device.write_vectored(&[IoSlice::new(&request)]).await.unwrap();
+    }
+
+    /// Read the next response frame, as its request id and delay.
+    async fn read_frame(device: &mut AsiSysreqDevice) -> (u32, u64) {
+        let mut header = [0u8; RPC_FRAME_HEADER_LEN];
+        assert_eq!(device.read_vectored(&mut [IoSliceMut::new(&mut header)]).await.unwrap(), RPC_FRAME_HEADER_LEN as u64);
+        let (request_id, len) = decode_frame_header(&header);
+
+        let mut resp = vec![0u8; len as usize];
+        device.read_vectored(&mut [IoSliceMut::new(&mut resp)]).await.unwrap();
+        let delay_ms: Result<u64, AsiRpcError> = RpcEncoding::Postcard.decode(&resp).unwrap();
+        (request_id, delay_ms.unwrap())
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_complete_out_of_order() {
+        let mut device = device();
+        submit(&mut device, 1, 200).await;
+        submit(&mut device, 2, 0).await;
+
+        // The slow request does not hold up the one behind it.
+        assert_eq!(read_frame(&mut device).await, (2, 0));
+        assert_eq!(read_frame(&mut device).await, (1, 200));
+    }
+
+    #[tokio::test]
+    async fn unpipelined_request_waits_for_pipelined_responses() {
+        let mut device = device();
+        submit(&mut device, 1, 50).await;
+
+        let request = encode_header(SleepRpcRequest::OP_CODE, RpcEncoding::Postcard.to_flags()).to_vec();
+        assert!(device.write_vectored(&[IoSlice::new(&request)]).await.is_err());
 
-        Ok(sz as u64)
+        assert_eq!(read_frame(&mut device).await, (1, 50));
     }
 }
\ No newline at end of file
@@ -0,0 +1,178 @@
+use std::sync::atomic::Ordering;
+
+use libasi_interop::{AsiRpcError, RpcRequest, diagnostics::{HelloRpcRequest, PokeRpcRequest, LogRpcRequest, LogBatchRpcRequest, LogFilterRpcRequest, LogLevel, LogField, LogSpan, LogValue}};
+use log::kv::{self, Source, VisitSource, Key, Value};
+
+use crate::{process::Process, process_log::LogEntryKind};
+
+use super::{SysreqContext, dispatch::{RpcDispatchTable, DispatchError}};
+
+pub fn register(table: &mut RpcDispatchTable) -> Result<(), DispatchError> {
+    table.register(hello)?;
+    table.register(poke)?;
+    table.register(log)?;
+    table.register(log_batch)?;
+    table.register(log_filter)?;
+    Ok(())
+}
+
+fn hello(_ctx: &SysreqContext, hello: HelloRpcRequest) -> Result<<HelloRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    log::info!("SYSREQ Hello {}", hello.who);
+    Ok(())
+}
+
+fn log(ctx: &SysreqContext, record: LogRpcRequest) -> Result<<LogRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    forward_record(&ctx.process, record);
+    Ok(())
+}
+
+fn log_batch(ctx: &SysreqContext, batch: LogBatchRpcRequest) -> Result<<LogBatchRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    for record in batch.records {
+        forward_record(&ctx.process, record);
+    }
+    Ok(())
+}
+
+/// Pass a guest record to the host logger.
+fn forward_record(process: &Process, record: LogRpcRequest) {
+    // Guests filter locally, this catches records sent before a filter change reached them.
+    if !process.log_filter.lock().expect("log filter lock poisoned").enabled(&record.target, record.level) {
+        return;
+    }
+
+    let level = match record.level {
+        LogLevel::Error => log::Level::Error,
+        LogLevel::Warn => log::Level::Warn,
+        LogLevel::Info => log::Level::Info,
+        LogLevel::Debug => log::Level::Debug,
+        LogLevel::Trace => log::Level::Trace,
+    };
+    let target = format!("GUEST:{}", record.target);
+    let key_values = GuestKeyValues::new(&record.spans, &record.fields);
+
+    let logger = log::logger();
+    logger.log(&log::Record::builder()
+        .level(level)
+        .target(&target)
+        .module_path(record.module_path.as_deref())
+        .file(record.file.as_deref())
+        .line(record.line)
+        .key_values(&key_values)
+        .args(format_args!("{}", record.body))
+        .build()
+    );
+
+    process.log.push(LogEntryKind::Record {
+        level: record.level,
+        target: record.target,
+        message: record.body,
+    });
+}
+
+fn log_filter(ctx: &SysreqContext, _request: LogFilterRpcRequest) -> Result<<LogFilterRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    Ok(ctx.process.log_filter.lock().expect("log filter lock poisoned").clone())
+}
+
+fn poke(ctx: &SysreqContext, _poke: PokeRpcRequest) -> Result<<PokeRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    Ok(ctx.poke_count.fetch_add(1, Ordering::Relaxed) + 1)
+}
+
+/// Key-values of a guest record as seen by the host logger.
+///
+/// The span path is reported under `span` (e.g. `request:db`), followed by the
+/// fields of each span, outermost first, and the fields of the record.
+struct GuestKeyValues<'a> {
+    span_path: Option<String>,
+    spans: &'a [LogSpan],
+    fields: &'a [LogField],
+}
+
+impl<'a> GuestKeyValues<'a> {
+    fn new(spans: &'a [LogSpan], fields: &'a [LogField]) -> Self {
+        let span_path = (!spans.is_empty())
+            .then(|| spans.iter().map(|span| span.name.as_str()).collect::<Vec<_>>().join(":"));
+
+        Self {
+            span_path,
+            spans,
+            fields,
+        }
+    }
+}
+
+impl<'a> Source for GuestKeyValues<'a> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        if let Some(span_path) = &self.span_path {
+            visitor.visit_pair(Key::from_str("span"), Value::from(span_path.as_str()))?;
+        }
+
+        let span_fields = self.spans.iter().flat_map(|span| span.fields.iter());
+        for field in span_fields.chain(self.fields.iter()) {
+            let value = match &field.value {
+                LogValue::Str(str) => Value::from(str.as_str()),
+                LogValue::I64(int) => Value::from(*int),
+                LogValue::U64(int) => Value::from(*int),
+                LogValue::F64(float) => Value::from(*float),
+                LogValue::Bool(bool) => Value::from(*bool),
+            };
+            visitor.visit_pair(Key::from_str(&field.key), value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libasi_interop::diagnostics::LogFilter;
+
+    use crate::{host_events::HostEventSender, json_log::tests::capture, process_log::ProcessLog};
+
+    use super::*;
+
+    fn process(filter: &str) -> Process {
+        let (events, _) = HostEventSender::channel();
+        Process::new(1, "test".to_string(), events, filter.parse().unwrap(), ProcessLog::new())
+    }
+
+    fn record(level: LogLevel) -> LogRpcRequest {
+        LogRpcRequest {
+            target: "app::net".to_string(),
+            level,
+            body: "retrying".to_string(),
+            module_path: Some("app::net::retry".to_string()),
+            file: Some("src/net/retry.rs".to_string()),
+            line: Some(42),
+            fields: vec![LogField { key: "attempt".to_string(), value: LogValue::U64(3) }],
+            spans: vec![LogSpan { name: "request".to_string(), fields: vec![] }],
+        }
+    }
+
+    #[test]
+    fn record_forwarded_with_guest_location() {
+        let process = process("info");
+        let lines = capture(|| forward_record(&process, record(LogLevel::Warn)));
+        assert_eq!(lines.len(), 1);
+
+        let line = &lines[0];
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "GUEST:app::net");
+        assert_eq!(line["module_path"], "app::net::retry");
+        assert_eq!(line["file"], "src/net/retry.rs");
+        assert_eq!(line["line"], 42);
+        assert_eq!(line["message"], "retrying");
+        assert_eq!(line["fields"], serde_json::json!({ "span": "request", "attempt": 3 }));
+
+        assert!(process.log.stream(LogFilter::new(Some(LogLevel::Trace)), false).try_recv().is_ok());
+    }
+
+    #[test]
+    fn record_rejected_by_process_filter() {
+        let process = process("info,app::net=warn");
+        let lines = capture(|| forward_record(&process, record(LogLevel::Info)));
+        assert!(lines.is_empty());
+
+        // Nor is it kept in the process's log.
+        assert!(process.log.stream(LogFilter::new(Some(LogLevel::Trace)), false).try_recv().is_err());
+    }
+}
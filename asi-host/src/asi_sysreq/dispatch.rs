@@ -0,0 +1,159 @@
+use std::{collections::HashMap, future::Future, marker::PhantomData, pin::Pin};
+
+use libasi_interop::{AsiRpcError, RpcRequest, encoding::{RpcEncoding, RPC_OP_CODE_MASK}};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::SysreqContext;
+
+/// Handler for a single RPC request type.
+pub type RpcHandlerFn<T> = fn(&SysreqContext, T) -> Result<<T as RpcRequest>::Response, AsiRpcError>;
+
+/// Future of an asynchronous RPC handler.
+pub type RpcFuture<'a, T> = BoxFuture<'a, Result<<T as RpcRequest>::Response, AsiRpcError>>;
+
+/// Asynchronous handler for a single RPC request type, for requests that wait
+/// on I/O. The guest is suspended, not its thread, while the future is pending.
+pub type AsyncRpcHandlerFn<T> = for<'a> fn(&'a SysreqContext, T) -> RpcFuture<'a, T>;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Error, Debug)]
+pub enum DispatchError {
+    #[error("opcode {op_code} registered by both {existing} and {duplicate}")]
+    DuplicateOpCode {
+        op_code: u32,
+        existing: &'static str,
+        duplicate: &'static str,
+    },
+    #[error("opcode {op_code} of {name} does not fit in a request header")]
+    OpCodeOutOfRange {
+        op_code: u32,
+        name: &'static str,
+    },
+}
+
+/// Type-erased handler, decodes the request, runs the handler and encodes the result.
+trait RpcHandler: Send + Sync {
+    fn handle<'a>(&'a self, ctx: &'a SysreqContext, encoding: RpcEncoding, request: &[u8]) -> BoxFuture<'a, Vec<u8>>;
+}
+
+struct TypedRpcHandler<T: RpcRequest> {
+    handler: RpcHandlerFn<T>,
+    _request: PhantomData<fn(T)>,
+}
+
+impl<T: RpcRequest> RpcHandler for TypedRpcHandler<T> {
+    fn handle<'a>(&'a self, ctx: &'a SysreqContext, encoding: RpcEncoding, request: &[u8]) -> BoxFuture<'a, Vec<u8>> {
+        let result = match encoding.decode::<T>(request) {
+            Ok(request) => (self.handler)(ctx, request),
+            Err(_) => Err(AsiRpcError::BadRequest),
+        };
+        Box::pin(std::future::ready(serialize_result(encoding, result)))
+    }
+}
+
+struct AsyncTypedRpcHandler<T: RpcRequest> {
+    handler: AsyncRpcHandlerFn<T>,
+    _request: PhantomData<fn(T)>,
+}
+
+impl<T: RpcRequest + Send> RpcHandler for AsyncTypedRpcHandler<T> {
+    fn handle<'a>(&'a self, ctx: &'a SysreqContext, encoding: RpcEncoding, request: &[u8]) -> BoxFuture<'a, Vec<u8>> {
+        // Decode up front so the future does not borrow the guest's buffers.
+        let request = encoding.decode::<T>(request).ok();
+        let handler = self.handler;
+        Box::pin(async move {
+            let result = match request {
+                Some(request) => handler(ctx, request).await,
+                None => Err(AsiRpcError::BadRequest),
+            };
+            serialize_result(encoding, result)
+        })
+    }
+}
+
+struct RpcHandlerEntry {
+    name: &'static str,
+    handler: Box<dyn RpcHandler>,
+}
+
+/// Table of RPC handlers keyed by [`RpcRequest::OP_CODE`].
+///
+/// Subsystems register their handlers once at host startup, the table is then
+/// shared by every sysreq device.
+pub struct RpcDispatchTable {
+    handlers: HashMap<u32, RpcHandlerEntry>,
+}
+
+impl RpcDispatchTable {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register the handler for requests of type `T`.
+    pub fn register<T: RpcRequest + 'static>(&mut self, handler: RpcHandlerFn<T>) -> Result<(), DispatchError> {
+        self.insert::<T>(Box::new(TypedRpcHandler::<T> {
+            handler,
+            _request: PhantomData,
+        }))
+    }
+
+    /// Register the asynchronous handler for requests of type `T`.
+    pub fn register_async<T: RpcRequest + Send + 'static>(&mut self, handler: AsyncRpcHandlerFn<T>) -> Result<(), DispatchError> {
+        self.insert::<T>(Box::new(AsyncTypedRpcHandler::<T> {
+            handler,
+            _request: PhantomData,
+        }))
+    }
+
+    fn insert<T: RpcRequest>(&mut self, handler: Box<dyn RpcHandler>) -> Result<(), DispatchError> {
+        let name = std::any::type_name::<T>();
+        if T::OP_CODE & !RPC_OP_CODE_MASK != 0 {
+            return Err(DispatchError::OpCodeOutOfRange {
+                op_code: T::OP_CODE,
+                name,
+            });
+        }
+        if let Some(existing) = self.handlers.get(&T::OP_CODE) {
+            return Err(DispatchError::DuplicateOpCode {
+                op_code: T::OP_CODE,
+                existing: existing.name,
+                duplicate: name,
+            });
+        }
+
+        self.handlers.insert(T::OP_CODE, RpcHandlerEntry {
+            name,
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Service an encoded request, returning the encoded response.
+    pub async fn dispatch(&self, ctx: &SysreqContext, op_code: u32, encoding: RpcEncoding, request: &[u8]) -> Vec<u8> {
+        match self.handlers.get(&op_code) {
+            Some(entry) => entry.handler.handle(ctx, encoding, request).await,
+            None => serialize_result(encoding, Err::<(), _>(AsiRpcError::BadRequest)),
+        }
+    }
+
+    /// All registered opcodes, in ascending order.
+    pub fn op_codes(&self) -> Vec<u32> {
+        let mut op_codes: Vec<u32> = self.handlers.keys().copied().collect();
+        op_codes.sort_unstable();
+        op_codes
+    }
+}
+
+impl Default for RpcDispatchTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn serialize_result<T: Serialize> (encoding: RpcEncoding, result: Result<T, AsiRpcError>) -> Vec<u8> {
+    encoding.encode(&result).unwrap_or_else(|_| vec![])
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use libasi_interop::{AsiRpcError, RpcRequest, events::{SetTimerRpcRequest, CancelTimerRpcRequest, HostEvent, MIN_TIMER_INTERVAL_MS, MAX_TIMERS}};
+
+use super::{SysreqContext, dispatch::{RpcDispatchTable, DispatchError}};
+
+pub fn register(table: &mut RpcDispatchTable) -> Result<(), DispatchError> {
+    table.register(set_timer)?;
+    table.register(cancel_timer)?;
+    Ok(())
+}
+
+fn set_timer(ctx: &SysreqContext, timer: SetTimerRpcRequest) -> Result<<SetTimerRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    if matches!(timer.interval_ms, Some(interval_ms) if interval_ms < MIN_TIMER_INTERVAL_MS) {
+        return Err(AsiRpcError::BadRequest);
+    }
+
+    // Expired one-shot timers no longer count against the limit.
+    let mut timers = ctx.timers.lock().expect("timers lock poisoned");
+    timers.retain(|_, task| !task.is_finished());
+    if timers.len() >= MAX_TIMERS && !timers.contains_key(&timer.timer_id) {
+        return Err(AsiRpcError::BadRequest);
+    }
+
+    let events = ctx.process.events.clone();
+    let task = tokio::spawn(async move {
+        let mut delay = Duration::from_millis(timer.delay_ms);
+        loop {
+            tokio::time::sleep(delay).await;
+            if !events.send(HostEvent::Timer { timer_id: timer.timer_id }) {
+                break;
+            }
+
+            match timer.interval_ms {
+                Some(interval_ms) => delay = Duration::from_millis(interval_ms),
+                None => break,
+            }
+        }
+    });
+
+    if let Some(previous) = timers.insert(timer.timer_id, task) {
+        previous.abort();
+    }
+    Ok(())
+}
+
+fn cancel_timer(ctx: &SysreqContext, cancel: CancelTimerRpcRequest) -> Result<<CancelTimerRpcRequest as RpcRequest>::Response, AsiRpcError> {
+    let removed = ctx.timers.lock().expect("timers lock poisoned").remove(&cancel.timer_id);
+    match removed {
+        Some(task) => {
+            task.abort();
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
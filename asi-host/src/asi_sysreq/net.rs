@@ -0,0 +1,174 @@
+use std::{io, net::{SocketAddr, Ipv4Addr, Ipv6Addr}, time::Duration};
+
+use libasi_interop::{net::{BindRpcRequest, BindAddr, ConnectRpcRequest, LookupRpcRequest, NetError, ConnectAddrs, ResolveRpcRequest, AddressFamily, RecordType, DnsRecord}};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use wasi_common::{WasiFile, file::FileCaps};
+
+use crate::{net_policy::{NetOp, NetTarget}, tcp_socket::{TcpListenerFile, TcpStreamFile}, udp_socket::UdpSocketFile};
+
+use super::{SysreqContext, dispatch::{RpcDispatchTable, RpcFuture, DispatchError}};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn register(table: &mut RpcDispatchTable) -> Result<(), DispatchError> {
+    table.register_async(bind)?;
+    table.register_async(connect)?;
+    table.register_async(lookup)?;
+    table.register_async(resolve)?;
+    Ok(())
+}
+
+fn bind(ctx: &SysreqContext, bind: BindRpcRequest) -> RpcFuture<'_, BindRpcRequest> {
+    Box::pin(async move {
+        let (BindAddr::Tcp { addr } | BindAddr::Udp { addr }) = &bind.bind_addr;
+        if !ctx.net_policy.check(NetOp::Bind, NetTarget::Addr(*addr)) {
+            return Ok(Err(NetError::AccessDenied));
+        }
+
+        match bind.bind_addr {
+            BindAddr::Tcp { addr } => {
+                let file = match TcpListener::bind(addr).await.and_then(tcp_listener_file) {
+                    Ok(file) => file,
+                    Err(err) => return Ok(Err(net_error(&err))),
+                };
+
+                Ok(ctx.handoff.reserve(file, socket_caps()).ok_or(NetError::Failed))
+            },
+            BindAddr::Udp { addr } => {
+                let socket = match UdpSocket::bind(addr).await {
+                    Ok(socket) => socket,
+                    Err(err) => return Ok(Err(net_error(&err))),
+                };
+
+                Ok(ctx.handoff.reserve(Box::new(UdpSocketFile::new(socket)), socket_caps()).ok_or(NetError::Failed))
+            },
+        }
+    })
+}
+
+fn connect(ctx: &SysreqContext, mut connect: ConnectRpcRequest) -> RpcFuture<'_, ConnectRpcRequest> {
+    Box::pin(async move {
+        // Drop addresses the policy denies, the call is only denied if none remain.
+        let (ConnectAddrs::Tcp { addrs } | ConnectAddrs::Udp { addrs }) = &mut connect.target;
+        if !addrs.is_empty() {
+            addrs.retain(|addr| ctx.net_policy.check(NetOp::Connect, NetTarget::Addr(*addr)));
+            if addrs.is_empty() {
+                return Ok(Err(NetError::AccessDenied));
+            }
+        }
+
+        match connect.target {
+            ConnectAddrs::Tcp { addrs } => {
+                // Try each address in order, reporting the last failure if none connect.
+                let mut result = Err(NetError::Failed);
+                for addr in addrs {
+                    result = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                        Ok(connected) => connected.and_then(tcp_stream_file).map_err(|err| net_error(&err)),
+                        Err(_) => Err(NetError::TimedOut),
+                    };
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+
+                let file = match result {
+                    Ok(file) => file,
+                    Err(err) => return Ok(Err(err)),
+                };
+
+                Ok(ctx.handoff.reserve(file, socket_caps()).ok_or(NetError::Failed))
+            },
+            ConnectAddrs::Udp { addrs } => {
+                // Bind an ephemeral local port of the right family and associate it with the first usable peer.
+                let mut result = Err(NetError::Failed);
+                for addr in addrs {
+                    let local: SocketAddr = match addr {
+                        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                    };
+                    result = match UdpSocket::bind(local).await {
+                        Ok(socket) => socket.connect(addr).await.map(|()| socket),
+                        Err(err) => Err(err),
+                    }.map_err(|err| net_error(&err));
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+
+                let socket = match result {
+                    Ok(socket) => socket,
+                    Err(err) => return Ok(Err(err)),
+                };
+
+                Ok(ctx.handoff.reserve(Box::new(UdpSocketFile::new(socket)), socket_caps()).ok_or(NetError::Failed))
+            },
+        }
+    })
+}
+
+fn lookup(ctx: &SysreqContext, lookup: LookupRpcRequest) -> RpcFuture<'_, LookupRpcRequest> {
+    Box::pin(async move {
+        let (name, port) = split_host_port(&lookup.query);
+        if !ctx.net_policy.check(NetOp::Lookup, NetTarget::lookup(name, port)) {
+            return Ok(Err(NetError::AccessDenied));
+        }
+
+        let records = match ctx.resolver.resolve(name, port, AddressFamily::Any, RecordType::Address).await {
+            Ok(records) => records,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        Ok(Ok(records.into_iter()
+            .filter_map(|record| match record {
+                DnsRecord::Addr(addr) => Some(addr),
+                _ => None,
+            })
+            .collect()))
+    })
+}
+
+fn resolve(ctx: &SysreqContext, resolve: ResolveRpcRequest) -> RpcFuture<'_, ResolveRpcRequest> {
+    Box::pin(async move {
+        if !ctx.net_policy.check(NetOp::Lookup, NetTarget::lookup(&resolve.hostname, resolve.port)) {
+            return Ok(Err(NetError::AccessDenied));
+        }
+
+        Ok(ctx.resolver.resolve(&resolve.hostname, resolve.port, resolve.family, resolve.record_type).await)
+    })
+}
+
+/// Guest file of a TCP listener.
+fn tcp_listener_file(listener: TcpListener) -> io::Result<Box<dyn WasiFile>> {
+    Ok(Box::new(TcpListenerFile::new(listener)))
+}
+
+/// Guest file of a connected TCP stream.
+fn tcp_stream_file(stream: TcpStream) -> io::Result<Box<dyn WasiFile>> {
+    Ok(Box::new(TcpStreamFile::new(stream, false)))
+}
+
+/// Split a lookup query into a hostname and an optional port.
+fn split_host_port(query: &str) -> (&str, Option<u16>) {
+    match query.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            (host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok())
+        },
+        _ => (query, None),
+    }
+}
+
+/// Capabilities granted to the guest on sockets created by the host.
+fn socket_caps() -> FileCaps {
+    FileCaps::READ | FileCaps::WRITE | FileCaps::POLL_READWRITE | FileCaps::FDSTAT_SET_FLAGS | FileCaps::FILESTAT_GET
+}
+
+fn net_error(err: &io::Error) -> NetError {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => NetError::ConnectionRefused,
+        io::ErrorKind::TimedOut => NetError::TimedOut,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => NetError::Unreachable,
+        io::ErrorKind::AddrInUse => NetError::AddressInUse,
+        io::ErrorKind::PermissionDenied => NetError::AccessDenied,
+        _ => NetError::Failed,
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+
+use libasi_interop::AsiFd;
+use wasi_common::{WasiCtx, WasiFile, file::FileCaps};
+
+/// Files created by the host on behalf of a guest that still have to be
+/// installed in the guest's WASI table.
+///
+/// The sysreq device is itself an entry in the WASI table, so it cannot insert
+/// new files while it is servicing a request. Instead it reserves a descriptor
+/// here, replies with it, and the host installs the file once the guest returns
+/// from the host call (see [`FdHandoff::install`]).
+#[derive(Clone)]
+pub struct FdHandoff {
+    inner: Arc<Mutex<FdHandoffInner>>,
+}
+
+struct FdHandoffInner {
+    next_fd: u32,
+    queued: Vec<(u32, Box<dyn WasiFile>, FileCaps)>,
+}
+
+impl FdHandoff {
+    /// First descriptor handed out by the handoff. This is far above the range
+    /// the WASI table allocates from, so reserved descriptors never collide
+    /// with files the guest opens itself.
+    const FD_BASE: u32 = 0x4000_0000;
+
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(FdHandoffInner {
+                next_fd: Self::FD_BASE,
+                queued: Vec::new(),
+            })),
+        }
+    }
+
+    /// Reserve a descriptor for `file`, returns `None` if descriptors are exhausted.
+    pub fn reserve(&self, file: Box<dyn WasiFile>, caps: FileCaps) -> Option<AsiFd> {
+        let mut inner = self.inner.lock().expect("fd handoff lock poisoned");
+
+        let fd = inner.next_fd;
+        let guest_fd = AsiFd::try_from(fd).ok()?;
+        inner.next_fd = fd.checked_add(1)?;
+        inner.queued.push((fd, file, caps));
+
+        Some(guest_fd)
+    }
+
+    /// Install all reserved files in the guest's WASI table.
+    ///
+    /// Must be called before the guest regains control after a host call that
+    /// may have reserved descriptors.
+    pub fn install(&self, wasi: &mut WasiCtx) {
+        let mut inner = self.inner.lock().expect("fd handoff lock poisoned");
+        for (fd, file, caps) in inner.queued.drain(..) {
+            wasi.insert_file(fd, file, caps);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::limits::LimitsConfig;
+
+/// Host settings, read from a JSON file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct HostConfig {
+    pub limits: LimitsConfig,
+    /// Threads processes run on, one per CPU if not set.
+    pub worker_threads: Option<usize>,
+    /// Terminated processes kept in the process table with their logs and
+    /// output, the oldest are removed first. 64 if not set.
+    pub retained_processes: Option<usize>,
+    /// Directory the host keeps its state in, `asi-state` in the working
+    /// directory if not set.
+    pub state_dir: Option<PathBuf>,
+}
+
+impl HostConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Terminated processes kept in the process table.
+    pub fn retained_processes(&self) -> usize {
+        self.retained_processes.unwrap_or(64)
+    }
+
+    /// Directory uploaded modules are stored in.
+    pub fn module_registry_dir(&self) -> PathBuf {
+        self.state_dir().join("modules")
+    }
+
+    /// Directory compiled modules are cached in.
+    pub fn module_cache_dir(&self) -> PathBuf {
+        self.state_dir().join("module-cache")
+    }
+
+    /// Directory process output routed to files is written in.
+    pub fn output_dir(&self) -> PathBuf {
+        self.state_dir().join("output")
+    }
+
+    fn state_dir(&self) -> &Path {
+        self.state_dir.as_deref().unwrap_or(Path::new("asi-state"))
+    }
+}
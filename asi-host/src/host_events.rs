@@ -0,0 +1,270 @@
+use std::{collections::{HashSet, VecDeque}, sync::{Arc, Mutex}};
+
+use libasi_interop::events::{HostEvent, encode_event_frame};
+use tokio::sync::Notify;
+use wasi_common::{WasiFile, file::{FdFlags, FileType}, Error, snapshots::preview_1::types::Errno};
+
+/// Sending half of a guest's host event channel.
+///
+/// Events are queued in the host and read by the guest from its event
+/// descriptor, which it can wait on with `poll_oneoff`. A guest that stops
+/// reading never blocks the host.
+///
+/// The queue is bounded, events sent while it is full are dropped. A timer
+/// event is not queued again until the guest has read its previous event.
+pub struct HostEventSender {
+    channel: Arc<EventChannel>,
+}
+
+/// Guest end of a host event channel, reads return event frames.
+pub struct HostEventFile {
+    channel: Arc<EventChannel>,
+    /// Rest of the frame the guest is reading.
+    frame: VecDeque<u8>,
+    nonblocking: bool,
+}
+
+struct EventChannel {
+    queue: Mutex<EventQueue>,
+    /// Signalled when an event is queued or the channel closes.
+    notify: Notify,
+}
+
+struct EventQueue {
+    events: VecDeque<HostEvent>,
+    /// Timers with an event the guest has not read yet.
+    queued_timers: HashSet<u64>,
+    senders: usize,
+    /// Set once the guest's file is dropped.
+    guest_gone: bool,
+}
+
+impl HostEventSender {
+    /// Events queued for a guest that is not reading them.
+    const QUEUE_LEN: usize = 128;
+
+    /// Create an event channel, returning the sender and the guest's file.
+    pub fn channel() -> (Self, Box<dyn WasiFile>) {
+        let channel = Arc::new(EventChannel {
+            queue: Mutex::new(EventQueue {
+                events: VecDeque::new(),
+                queued_timers: HashSet::new(),
+                senders: 1,
+                guest_gone: false,
+            }),
+            notify: Notify::new(),
+        });
+
+        let file = HostEventFile {
+            channel: channel.clone(),
+            frame: VecDeque::new(),
+            nonblocking: false,
+        };
+        (Self { channel }, Box::new(file))
+    }
+
+    /// Queue `event` for the guest, returns false if the guest is gone.
+    pub fn send(&self, event: HostEvent) -> bool {
+        let mut queue = self.channel.lock();
+        if queue.guest_gone {
+            return false;
+        }
+
+        if let HostEvent::Timer { timer_id } = event {
+            if queue.queued_timers.contains(&timer_id) {
+                // Folded into the event the guest has yet to read.
+                return true;
+            }
+        }
+        if queue.events.len() >= Self::QUEUE_LEN {
+            log::warn!("Host event queue full, dropping {:?}", event);
+            return true;
+        }
+
+        if let HostEvent::Timer { timer_id } = event {
+            queue.queued_timers.insert(timer_id);
+        }
+        queue.events.push_back(event);
+        self.channel.notify.notify_one();
+        true
+    }
+}
+
+impl Clone for HostEventSender {
+    fn clone(&self) -> Self {
+        self.channel.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl Drop for HostEventSender {
+    fn drop(&mut self) {
+        // The guest reads the end of the channel once the last sender is gone.
+        self.channel.lock().senders -= 1;
+        self.channel.notify.notify_one();
+    }
+}
+
+impl EventChannel {
+    fn lock(&self) -> std::sync::MutexGuard<'_, EventQueue> {
+        self.queue.lock().expect("host event queue lock poisoned")
+    }
+
+    /// Wait until an event is queued or every sender is gone.
+    async fn ready(&self) {
+        loop {
+            {
+                let queue = self.lock();
+                if !queue.events.is_empty() || queue.senders == 0 {
+                    return;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl HostEventFile {
+    /// Move the next event into the frame being read, false at the end of
+    /// the channel.
+    async fn next_frame(&mut self) -> Result<bool, Error> {
+        loop {
+            {
+                let mut queue = self.channel.lock();
+                if let Some(event) = queue.events.pop_front() {
+                    // The guest is reading the timer's event, the next expiry is queued again.
+                    if let HostEvent::Timer { timer_id } = event {
+                        queue.queued_timers.remove(&timer_id);
+                    }
+                    drop(queue);
+
+                    match encode_event_frame(&event) {
+                        Ok(frame) => self.frame.extend(frame),
+                        Err(err) => {
+                            log::error!("Failed to encode host event {:?}: {}", event, err);
+                            continue;
+                        },
+                    }
+                    return Ok(true);
+                }
+                if queue.senders == 0 {
+                    return Ok(false);
+                }
+                if self.nonblocking {
+                    return Err(Errno::Again.into());
+                }
+            }
+            self.channel.ready().await;
+        }
+    }
+}
+
+impl Drop for HostEventFile {
+    fn drop(&mut self) {
+        self.channel.lock().guest_gone = true;
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for HostEventFile {
+    fn as_any(&self) ->  &dyn std::any::Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::CharacterDevice)
+    }
+
+    async fn read_vectored<'a> (&mut self, bufs: &mut [std::io::IoSliceMut<'a>]) -> Result<u64, Error> {
+        if self.frame.is_empty() && !self.next_frame().await? {
+            return Ok(0);
+        }
+
+        // Frames are read in as many reads as the guest takes.
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let sz = self.frame.len().min(buf.len());
+            for (dst, src) in buf[..sz].iter_mut().zip(self.frame.drain(..sz)) {
+                *dst = src;
+            }
+            read += sz;
+        }
+
+        Ok(read as u64)
+    }
+
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(if self.nonblocking { FdFlags::NONBLOCK } else { FdFlags::empty() })
+    }
+
+    async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+        if !(fdflags - FdFlags::NONBLOCK).is_empty() {
+            return Err(Errno::Inval.into());
+        }
+        self.nonblocking = fdflags.contains(FdFlags::NONBLOCK);
+        Ok(())
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        if self.frame.is_empty() {
+            self.channel.ready().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSliceMut;
+
+    use libasi_interop::events::{EVENT_FRAME_HEADER_LEN, decode_event_frame_header, decode_event};
+
+    use super::*;
+
+    /// Read one event from `file` as a guest does, header then body.
+    async fn read_event(file: &mut Box<dyn WasiFile>) -> Option<HostEvent> {
+        let mut header = [0u8; EVENT_FRAME_HEADER_LEN];
+        if file.read_vectored(&mut [IoSliceMut::new(&mut header)]).await.unwrap() == 0 {
+            return None;
+        }
+        let mut body = vec![0u8; decode_event_frame_header(&header) as usize];
+        file.read_vectored(&mut [IoSliceMut::new(&mut body)]).await.unwrap();
+        Some(decode_event(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn events_in_order() {
+        let (sender, mut file) = HostEventSender::channel();
+        assert!(sender.send(HostEvent::Timer { timer_id: 1 }));
+        assert!(sender.send(HostEvent::Shutdown));
+
+        assert_eq!(read_event(&mut file).await, Some(HostEvent::Timer { timer_id: 1 }));
+        assert_eq!(read_event(&mut file).await, Some(HostEvent::Shutdown));
+
+        drop(sender);
+        assert_eq!(read_event(&mut file).await, None);
+    }
+
+    #[tokio::test]
+    async fn timer_queued_again_once_read() {
+        let (sender, mut file) = HostEventSender::channel();
+        sender.send(HostEvent::Timer { timer_id: 1 });
+        sender.send(HostEvent::Timer { timer_id: 1 });
+        assert_eq!(read_event(&mut file).await, Some(HostEvent::Timer { timer_id: 1 }));
+
+        // Expiring while the guest reads the previous event queues it again.
+        sender.send(HostEvent::Timer { timer_id: 1 });
+        sender.send(HostEvent::Shutdown);
+        assert_eq!(read_event(&mut file).await, Some(HostEvent::Timer { timer_id: 1 }));
+        assert_eq!(read_event(&mut file).await, Some(HostEvent::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn send_fails_once_guest_gone() {
+        let (sender, file) = HostEventSender::channel();
+        drop(file);
+        assert!(!sender.send(HostEvent::Shutdown));
+    }
+}
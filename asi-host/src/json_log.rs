@@ -0,0 +1,144 @@
+use std::{io::{self, Write}, time::{SystemTime, UNIX_EPOCH}};
+
+use log::{Record, kv::{self, VisitSource, Key, Value}};
+use serde_json::{Map, Number};
+
+/// Write `record` as a single line JSON object, keeping its key-values as
+/// typed fields.
+pub fn write_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut fields = FieldCollector(Map::new());
+    // Collection itself never fails, keep whatever was visited.
+    let _ = record.key_values().visit(&mut fields);
+
+    let line = serde_json::json!({
+        "timestamp_ms": timestamp.as_millis() as u64,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "module_path": record.module_path(),
+        "file": record.file(),
+        "line": record.line(),
+        "message": record.args().to_string(),
+        "fields": fields.0,
+    });
+
+    serde_json::to_writer(&mut *out, &line)?;
+    writeln!(out)
+}
+
+struct FieldCollector(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(str) = value.to_borrowed_str() {
+            serde_json::Value::from(str)
+        } else if let Some(bool) = value.to_bool() {
+            serde_json::Value::from(bool)
+        } else if let Some(int) = value.to_i64() {
+            serde_json::Value::from(int)
+        } else if let Some(int) = value.to_u64() {
+            serde_json::Value::from(int)
+        } else if let Some(float) = value.to_f64().and_then(Number::from_f64) {
+            serde_json::Value::Number(float)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{cell::RefCell, sync::Once};
+
+    use log::{Log, Metadata};
+
+    use super::*;
+
+    thread_local!(
+        /// Lines written on this thread, tests run in parallel and other modules log too.
+        static CAPTURED: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) }
+    );
+
+    /// Logger writing records as the host does with a JSON log file.
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            CAPTURED.with(|captured| write_record(&mut *captured.borrow_mut(), record).unwrap());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+    static INIT: Once = Once::new();
+
+    /// Lines logged on this thread by `f`, parsed.
+    pub(crate) fn capture(f: impl FnOnce()) -> Vec<serde_json::Value> {
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        CAPTURED.with(|captured| captured.borrow_mut().clear());
+        f();
+        let output = CAPTURED.with(|captured| captured.take());
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.is_empty() || output.ends_with('\n'));
+        output.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn record_fields() {
+        let lines = capture(|| log::warn!(target: "asi::net", "denied {}", "10.0.0.1"));
+        assert_eq!(lines.len(), 1);
+
+        let line = &lines[0];
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "asi::net");
+        assert_eq!(line["message"], "denied 10.0.0.1");
+        assert_eq!(line["module_path"], module_path!());
+        assert_eq!(line["file"], file!());
+        assert!(line["line"].is_u64());
+        assert!(line["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(line["fields"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn typed_key_values() {
+        let lines = capture(|| {
+            log::info!(pid = 7, delta = -3, ratio = 0.25, exited = true, name = "app", addr:% = "[::1]:80"; "process exited");
+        });
+
+        assert_eq!(lines[0]["fields"], serde_json::json!({
+            "pid": 7,
+            "delta": -3,
+            "ratio": 0.25,
+            "exited": true,
+            "name": "app",
+            "addr": "[::1]:80",
+        }));
+    }
+
+    #[test]
+    fn one_line_per_record() {
+        let lines = capture(|| {
+            log::error!("first\nsecond");
+            log::trace!(target: "cranelift", "third");
+        });
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "first\nsecond");
+        assert_eq!(lines[1]["level"], "TRACE");
+        assert_eq!(lines[1]["target"], "cranelift");
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+/// Resource limits of a process, unlimited where not set.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Maximum size of each linear memory, in bytes.
+    pub memory_bytes: Option<u64>,
+    /// Maximum number of elements of each table.
+    pub table_elements: Option<u32>,
+    /// Fuel the guest may consume, roughly one unit per wasm instruction.
+    pub fuel: Option<u64>,
+    /// Wall-clock time the process may run for, in milliseconds.
+    pub wall_time_ms: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Limits set here, or in `defaults` where not set.
+    pub fn or(self, defaults: &ResourceLimits) -> Self {
+        Self {
+            memory_bytes: self.memory_bytes.or(defaults.memory_bytes),
+            table_elements: self.table_elements.or(defaults.table_elements),
+            fuel: self.fuel.or(defaults.fuel),
+            wall_time_ms: self.wall_time_ms.or(defaults.wall_time_ms),
+        }
+    }
+
+    /// The tighter of the limits set here and in `max`.
+    pub fn capped(self, max: &ResourceLimits) -> Self {
+        fn min<T: Ord>(limit: Option<T>, max: Option<T>) -> Option<T> {
+            match (limit, max) {
+                (Some(limit), Some(max)) => Some(limit.min(max)),
+                (limit, max) => limit.or(max),
+            }
+        }
+
+        Self {
+            memory_bytes: min(self.memory_bytes, max.memory_bytes),
+            table_elements: min(self.table_elements, max.table_elements),
+            fuel: min(self.fuel, max.fuel),
+            wall_time_ms: min(self.wall_time_ms, max.wall_time_ms),
+        }
+    }
+}
+
+/// Host wide process limits.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Limits of processes whose run request does not set them.
+    pub default: ResourceLimits,
+    /// Limits no process may exceed, whatever its run request asks for.
+    pub max: ResourceLimits,
+}
+
+impl LimitsConfig {
+    /// Limits of a process whose run request asked for `requested`.
+    pub fn resolve(&self, requested: ResourceLimits) -> ResourceLimits {
+        requested.or(&self.default).capped(&self.max)
+    }
+}
+
+/// Limit a process was terminated for exceeding.
+#[derive(Error, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitExceeded {
+    #[error("memory limit exceeded")]
+    Memory,
+    #[error("table limit exceeded")]
+    Table,
+    #[error("fuel exhausted")]
+    Fuel,
+    #[error("wall-clock time limit exceeded")]
+    WallTime,
+}
@@ -1,54 +1,144 @@
-use std::{path::Path, thread::JoinHandle};
-
-use asi_sysreq::AsiSysreqDevice;
+use std::{borrow::Cow, collections::BTreeMap, future::Future, path::Path, sync::{mpsc, Arc, Mutex}, time::Duration};
+
+use asi_sysreq::{AsiSysreqDevice, RpcDispatchTable, SysreqContext};
+use fd_handoff::FdHandoff;
+use host_config::HostConfig;
+use host_events::HostEventSender;
+use libasi_interop::{diagnostics::{LogFilter, LogLevel, LOG_FILTER_CONFIG_KEY}, events::{HostEvent, EVENTS_FD_ENV}};
+use limits::{LimitExceeded, LimitsConfig, ResourceLimits};
 use log::LevelFilter;
-use wasi_common::{file::{FileType, FileCaps}, Error};
-use wasmtime::{Engine, Store, Linker, Module};
-use wasmtime_wasi::{WasiCtxBuilder, WasiFile};
+use module_cache::ModuleCache;
+use module_registry::{ModuleRef, ModuleRegistry};
+use net_policy::NetPolicy;
+use process::{Process, ProcessInfo, ProcessState, Killed, TrapFrame};
+use process_log::{ProcessLog, LogEntryKind};
+use process_output::{ProcessOutput, OutputRoute, OutputStream};
+use resolver::Resolver;
+use tokio::{runtime::Runtime, task::JoinHandle};
+use wasi_common::file::FileCaps;
+use wasmtime::{Config, Engine, Store, Linker, CallHook, Module, ResourceLimiter, Trap, WasmBacktrace};
+use wasmtime_wasi::{WasiCtx, I32Exit, tokio::WasiCtxBuilder};
 
 use crate::uds_server::{UdsControlServer, ClientRequest};
 
 pub mod asi_sysreq;
+pub mod fd_handoff;
+pub mod host_config;
+pub mod host_events;
+pub mod json_log;
+pub mod limits;
+pub mod module_cache;
+pub mod module_registry;
+pub mod net_policy;
+pub mod process;
+pub mod process_log;
+pub mod process_output;
+pub mod resolver;
+pub mod tcp_socket;
 pub mod uds_server;
-
-struct OutputHandler {
-
+pub mod udp_socket;
+
+/// Host side handle to a process.
+struct ProcessHandle {
+    /// Process task, taken once reaped.
+    join: Option<JoinHandle<anyhow::Result<()>>>,
+    process: Arc<Process>,
+    output: ProcessOutput,
 }
 
-#[async_trait::async_trait]
-impl WasiFile for OutputHandler {
-    fn as_any(&self) ->  &dyn std::any::Any {
-        self
-    }
+/// Store data of a process.
+struct ProcessStore {
+    wasi: WasiCtx,
+    process: Arc<Process>,
+    limits: ResourceLimits,
+    /// Limit the guest tried to grow past. The growth fails, and the process
+    /// is terminated for exceeding the limit once its call returns.
+    exceeded: Option<LimitExceeded>,
+}
 
-    async fn get_filetype(&mut self) -> Result<FileType, Error> {
-        Ok(FileType::CharacterDevice)
+impl ResourceLimiter for ProcessStore {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        if matches!(self.limits.memory_bytes, Some(max) if desired as u64 > max) {
+            self.exceeded = Some(LimitExceeded::Memory);
+            return false;
+        }
+        self.process.stats.grow_memory(desired - current);
+        true
     }
 
-    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
-        let mut len = 0;
-        for buf in bufs {
-            if let Ok(str) = std::str::from_utf8(&buf) {
-                print!("{}", str);
-            }
-            len += buf.len();
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        if matches!(self.limits.table_elements, Some(max) if desired > max) {
+            self.exceeded = Some(LimitExceeded::Table);
+            return false;
         }
-        //println!("wrote {} chars", len);
-        Ok(len as u64)
+        true
     }
 }
 
+/// Process table of the host, shared with the spawns completing on the runtime.
+type ProcessTable = Arc<Mutex<BTreeMap<u32, ProcessHandle>>>;
+
 struct AsiBasicHost {
+    /// Runtime the processes run on, they yield its threads to each other.
+    runtime: Runtime,
     engine: Engine,
-    processes: Vec<JoinHandle<anyhow::Result<()>>>,
+    /// Shared with the compilations running on the runtime.
+    modules: Arc<Mutex<ModuleCache>>,
+    dispatch: Arc<RpcDispatchTable>,
+    resolver: Arc<Resolver>,
+    /// Log filter new processes start with.
+    guest_log_filter: LogFilter,
+    limits: LimitsConfig,
+    /// Terminated processes kept in the process table.
+    retained_processes: usize,
+    /// Directory file output routes are relative to.
+    output_dir: Arc<Path>,
+    next_pid: u32,
+    processes: ProcessTable,
 }
 
 impl AsiBasicHost {
-    pub fn new() -> Self {
-        Self {
-            engine: Engine::default(),
-            processes: Vec::new(),
-        }
+    /// How long a kill request waits for the process to stop.
+    const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Interval of the engine epoch, at which running guests yield.
+    const EPOCH_TICK: Duration = Duration::from_millis(50);
+
+    /// Fuel of processes without a fuel limit, fuel is a signed 64-bit counter.
+    const UNLIMITED_FUEL: u64 = i64::MAX as u64;
+
+    pub fn new(runtime: Runtime, dispatch: RpcDispatchTable, resolver: Resolver, guest_log_filter: LogFilter, host_config: HostConfig) -> anyhow::Result<Self> {
+        // Guests run asynchronously and are interrupted through epochs so they
+        // share the runtime's threads, and consume fuel so their CPU use can be bounded.
+        let mut config = Config::new();
+        config.async_support(true);
+        config.epoch_interruption(true);
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let modules = ModuleCache::open(engine.clone(), host_config.module_cache_dir())?;
+
+        let ticker = engine.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(Self::EPOCH_TICK);
+            loop {
+                interval.tick().await;
+                ticker.increment_epoch();
+            }
+        });
+
+        Ok(Self {
+            runtime,
+            engine,
+            modules: Arc::new(Mutex::new(modules)),
+            dispatch: Arc::new(dispatch),
+            resolver: Arc::new(resolver),
+            guest_log_filter,
+            retained_processes: host_config.retained_processes(),
+            output_dir: host_config.output_dir().into(),
+            limits: host_config.limits,
+            next_pid: 1,
+            processes: Arc::new(Mutex::new(BTreeMap::new())),
+        })
     }
 
     /*
@@ -78,50 +168,339 @@ impl AsiBasicHost {
     }
     */
 
-    /// Start an aSi process from a module on the local disk.
-    pub fn spawn_process_data(&mut self, wasi_data: &[u8]) -> anyhow::Result<()> {
-        let mut linker = Linker::new(&self.engine);
-        wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
-        let mut wasi = WasiCtxBuilder::new().stdout(Box::new(OutputHandler{})).build();
+    /// Start an aSi process from a module on the local disk. The returned
+    /// future completes with the process once it is started, it runs on the
+    /// host's runtime and compiles the module on its blocking threads.
+    ///
+    /// The module is compiled unless an identical binary was compiled before, see [`ModuleCache`].
+    /// The process is named `name`, or after the module's name section if not provided.
+    /// Its limits are the `limits` it asked for, within the host's limits.
+    pub fn spawn_process_data(&mut self, wasi_data: Vec<u8>, name: Option<String>, net_policy: NetPolicy, output_route: OutputRoute, limits: ResourceLimits) -> impl Future<Output = anyhow::Result<Arc<Process>>> + Send + 'static {
+        let limits = self.limits.resolve(limits);
+
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        let engine = self.engine.clone();
+        let modules = self.modules.clone();
+        let dispatch = self.dispatch.clone();
+        let resolver = self.resolver.clone();
+        let guest_log_filter = self.guest_log_filter.clone();
+        let processes = self.processes.clone();
+        let retained_processes = self.retained_processes;
+        let output_dir = self.output_dir.clone();
+
+        async move {
+            let module = compile(modules, wasi_data).await?;
+            let name = name.as_deref().or(module.name()).unwrap_or("unnamed").to_string();
+
+            let mut linker = Linker::new(&engine);
+            wasmtime_wasi::tokio::add_to_linker(&mut linker, |s: &mut ProcessStore| &mut s.wasi)?;
+            let log = ProcessLog::new();
+            let output = ProcessOutput::new(pid, &output_route, &output_dir, log.clone())?;
+            let mut wasi = WasiCtxBuilder::new()
+                .stdout(output.handler(OutputStream::Stdout))
+                .stderr(output.handler(OutputStream::Stderr))
+                .build();
+
+            // Create the host event channel, readable by the guest with poll_oneoff.
+            let (events, events_file) = HostEventSender::channel();
+            let events_fd = wasi.push_file(events_file, FileCaps::READ | FileCaps::POLL_READWRITE | FileCaps::FDSTAT_SET_FLAGS | FileCaps::FILESTAT_GET)?;
+            wasi.push_env(EVENTS_FD_ENV, &events_fd.to_string())?;
+
+            // Create the a-Si RPC root device.
+            let handoff = FdHandoff::new();
+            let process = Arc::new(Process::new(pid, name, events, guest_log_filter, log.clone()));
+            let sysreq_ctx = SysreqContext::new(dispatch, handoff.clone(), net_policy, resolver, process.clone());
+            let sysreq_fd = wasi.push_file(Box::new(AsiSysreqDevice::new(sysreq_ctx)), FileCaps::READ | FileCaps::WRITE)?;
+            wasi.push_env("ASI_RPCROOT_FD", &sysreq_fd.to_string())?;
+
+            let mut store = Store::new(&engine, ProcessStore { wasi, process: process.clone(), limits, exceeded: None });
+
+            // Memory and table growth is limited, and tracked for the process stats.
+            store.limiter(|data| data);
+            store.add_fuel(limits.fuel.unwrap_or(Self::UNLIMITED_FUEL))?;
+
+            // Guests yield at every epoch tick, so a busy guest cannot hold on to a runtime
+            // thread and can be stopped.
+            store.epoch_deadline_async_yield_and_update(1);
+
+            // Files created by the sysreq device are installed before control returns to the guest.
+            store.call_hook(move |data, hook| {
+                if matches!(hook, CallHook::ReturningFromHost) {
+                    handoff.install(&mut data.wasi);
+                }
+                Ok(())
+            });
+
+            if let Err(err) = linker.module_async(&mut store, "", &module).await {
+                // Modules needing more than the process's limits to instantiate, such as a larger
+                // initial memory, are recorded as exceeding them like a running process would be.
+                let Some(limit) = exceeded_limit(store.data(), Some(&err)) else {
+                    return Err(err);
+                };
+                terminate(&process, &output, ProcessState::LimitExceeded { limit });
+                Self::insert(&processes, retained_processes, ProcessHandle {
+                    join: None,
+                    process: process.clone(),
+                    output,
+                });
+                return Ok(process);
+            }
 
-        // Create the a-Si RPC root device.
-        let sysreq_fd = wasi.push_file(Box::new(AsiSysreqDevice::new()), FileCaps::READ | FileCaps::WRITE)?;
-        wasi.push_env("ASI_RPCROOT_FD", &sysreq_fd.to_string())?;
+            let entry = linker
+                .get_default(&mut store, "")?
+                .typed::<(), ()>(&store)?;
+
+            // The deadline is watched by a task of its own, which terminates the process and
+            // kills the guest. The guest's call is dropped at its next yield, along with a host
+            // call it is waiting in.
+            let watchdog = limits.wall_time_ms.map(|wall_time_ms| {
+                let (process, output) = (process.clone(), output.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(wall_time_ms)).await;
+                    terminate(&process, &output, ProcessState::LimitExceeded { limit: LimitExceeded::WallTime });
+                    process.kill();
+                })
+            });
+
+            let task_process = process.clone();
+            let process_output = output.clone();
+            let join = tokio::spawn(async move {
+                task_process.set_state(ProcessState::Running);
+
+                // Dropping the call at a yield point stops the guest.
+                let result = tokio::select! {
+                    result = entry.call_async(&mut store, ()) => result,
+                    () = task_process.killed() => Err(Killed.into()),
+                };
+                if let Some(watchdog) = watchdog {
+                    watchdog.abort();
+                }
 
-        let mut store = Store::new(&self.engine, wasi);
+                // A guest failing or exiting after a growth past its limits was denied is
+                // terminated for exceeding them.
+                let state = match (&result, exceeded_limit(store.data(), result.as_ref().err())) {
+                    (Err(err), _) if err.is::<Killed>() => ProcessState::Killed,
+                    (_, Some(limit)) => ProcessState::LimitExceeded { limit },
+                    (Ok(()), None) => ProcessState::Exited { code: 0 },
+                    (Err(err), None) => match err.downcast_ref::<I32Exit>() {
+                        Some(exit) => ProcessState::Exited { code: exit.0 },
+                        None => trapped_state(err),
+                    },
+                };
+                terminate(&task_process, &process_output, state);
+                
+                result
+            });
+
+            Self::insert(&processes, retained_processes, ProcessHandle {
+                join: Some(join),
+                process: process.clone(),
+                output,
+            });
+            
+            Ok(process)
+        }
+    }
 
-        let module = Module::from_binary(&self.engine, wasi_data)?;
-        linker.module(&mut store, "", &module)?;
+    /// Add `handle` to the process table, reaping the oldest terminated
+    /// processes beyond the `retained` ones.
+    fn insert(processes: &ProcessTable, retained: usize, handle: ProcessHandle) {
+        let mut processes = processes.lock().expect("process table lock poisoned");
+        processes.insert(handle.process.pid, handle);
+        Self::reap(&mut processes, retained);
+    }
 
-        let entry = linker
-            .get_default(&mut store, "")?
-            .typed::<(), ()>(&store)?;
+    /// Remove the oldest terminated processes beyond the `retained` ones.
+    fn reap(processes: &mut BTreeMap<u32, ProcessHandle>, retained: usize) {
+        let terminated: Vec<u32> = processes.iter()
+            .filter(|(_, handle)| handle.process.state().is_terminated())
+            .map(|(pid, _)| *pid)
+            .collect();
+
+        let excess = terminated.len().saturating_sub(retained);
+        for pid in &terminated[..excess] {
+            log::debug!("Reaping process {}", pid);
+            processes.remove(pid);
+        }
+    }
 
-        let join = std::thread::spawn(move || {
-            let result = entry.call(&mut store, ());
+    /// Send `event` to every running process.
+    pub fn broadcast(&mut self, event: HostEvent) {
+        for process in self.processes.lock().expect("process table lock poisoned").values() {
+            process.process.events.send(event.clone());
+        }
+    }
 
-            if let Err(err) = &result {
-                log::warn!("Program crashed: {}", err)
-            }
-            
-            result
-        });
+    /// Change the log filter of process `pid` and notify the guest.
+    pub fn set_log_filter(&mut self, pid: u32, filter: LogFilter) -> anyhow::Result<()> {
+        let Some(process) = self.process(pid) else {
+            anyhow::bail!("no process {}", pid);
+        };
 
-        self.processes.push(join);
-        
+        let value = filter.to_string();
+        *process.log_filter.lock().expect("log filter lock poisoned") = filter;
+        process.events.send(HostEvent::ConfigChanged {
+            key: LOG_FILTER_CONFIG_KEY.to_string(),
+            value,
+        });
         Ok(())
     }
 
+    /// Process `pid`.
+    pub fn process(&self, pid: u32) -> Option<Arc<Process>> {
+        self.processes.lock().expect("process table lock poisoned").get(&pid).map(|process| process.process.clone())
+    }
+
+    /// Kill process `pid`. The returned future completes once the guest has
+    /// stopped, it waits on the host's runtime.
+    ///
+    /// The guest is stopped the next time it yields, at an epoch tick or while
+    /// waiting on the host, the host call it waits in is dropped with it. A
+    /// guest still running after [`Self::KILL_TIMEOUT`] fails the kill.
+    pub fn kill(&mut self, pid: u32) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + Send + 'static> {
+        let Some(process) = self.process(pid) else {
+            anyhow::bail!("no process {}", pid);
+        };
+        if process.state().is_terminated() {
+            anyhow::bail!("process {} is not running", pid);
+        }
+
+        process.kill();
+        self.engine.increment_epoch();
+
+        let (sender, exited) = mpsc::channel();
+        process.notify_exit(sender);
+        Ok(async move {
+            let stopped = tokio::task::spawn_blocking(move || exited.recv_timeout(Self::KILL_TIMEOUT).is_ok()).await?;
+            if !stopped {
+                anyhow::bail!("process {} did not stop within {}s of being killed", pid, Self::KILL_TIMEOUT.as_secs());
+            }
+            Ok(())
+        })
+    }
+
+    /// Captured log of process `pid`.
+    pub fn process_log(&self, pid: u32) -> Option<ProcessLog> {
+        self.process(pid).map(|process| process.log.clone())
+    }
+
+    /// Output of process `pid`.
+    pub fn process_output(&self, pid: u32) -> Option<ProcessOutput> {
+        self.processes.lock().expect("process table lock poisoned").get(&pid).map(|process| process.output.clone())
+    }
+
+    /// Snapshot of the process table, by process id.
+    pub fn list(&self) -> Vec<ProcessInfo> {
+        self.processes.lock().expect("process table lock poisoned").values().map(|process| process.process.info()).collect()
+    }
+
+    /// Compiled module cache of the host.
+    pub fn module_cache(&self) -> &Arc<Mutex<ModuleCache>> {
+        &self.modules
+    }
+
     /// Wait for all processes to terminate. 
     pub fn wait(&mut self) {
-        while let Some(join) = self.processes.pop() {
-            let _ = join.join();
+        let processes = std::mem::take(&mut *self.processes.lock().expect("process table lock poisoned"));
+        for (_, process) in processes {
+            if let Some(join) = process.join {
+                let _ = self.runtime.block_on(join);
+            }
         }
     }
 }
 
+/// Compile `binary` through the module cache, on the runtime's blocking threads.
+async fn compile(modules: Arc<Mutex<ModuleCache>>, binary: Vec<u8>) -> anyhow::Result<Module> {
+    tokio::task::spawn_blocking(move || ModuleCache::get_or_compile(&modules, &binary)).await?
+}
+
+/// Record that `process` terminated in `state` and end its log and output,
+/// unless its termination was already recorded.
+fn terminate(process: &Process, output: &ProcessOutput, state: ProcessState) {
+    if !process.claim_termination() {
+        return;
+    }
+
+    let record = match &state {
+        ProcessState::Killed => {
+            log::info!("Process {} killed", process.pid);
+            Some((LogLevel::Warn, "Killed by the host".to_string()))
+        },
+        ProcessState::LimitExceeded { limit } => {
+            log::info!("Process {} terminated: {}", process.pid, limit);
+            Some((LogLevel::Error, format!("Terminated: {}", limit)))
+        },
+        ProcessState::Trapped { message, .. } => {
+            log::warn!("Process {} crashed: {}", process.pid, message);
+            Some((LogLevel::Error, format!("Program crashed: {}", message)))
+        },
+        _ => None,
+    };
+    if let Some((level, message)) = record {
+        process.log.push(LogEntryKind::Record {
+            level,
+            target: "asi".to_string(),
+            message,
+        });
+    }
+
+    process.set_state(state);
+    process.log.close();
+    output.close();
+}
+
+/// Limit a process exceeded, if any, as recorded by its limiter or as the
+/// trap of its failure `err`.
+fn exceeded_limit(data: &ProcessStore, err: Option<&anyhow::Error>) -> Option<LimitExceeded> {
+    data.exceeded.or_else(|| match err?.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => Some(LimitExceeded::Fuel),
+        _ => None,
+    })
+}
+
+/// State of a process that failed with `err`, with the trap details if it trapped.
+fn trapped_state(err: &anyhow::Error) -> ProcessState {
+    let backtrace = err.downcast_ref::<WasmBacktrace>()
+        .map(|backtrace| {
+            backtrace.frames().iter()
+                .map(|frame| TrapFrame {
+                    module: frame.module_name().map(str::to_string),
+                    func_index: frame.func_index(),
+                    func_name: frame.func_name().map(str::to_string),
+                    module_offset: frame.module_offset(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ProcessState::Trapped {
+        message: err.root_cause().to_string(),
+        trap_code: err.downcast_ref::<Trap>().map(|trap| format!("{:?}", trap)),
+        backtrace,
+    }
+}
+
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).filter_module("cranelift_codegen", LevelFilter::Warn).init();
+    let mut logger = env_logger::builder();
+    logger.filter_level(LevelFilter::Info).filter_module("cranelift_codegen", LevelFilter::Warn);
+
+    // Logs go to the console unless a JSON log file is provided.
+    if let Some(path) = std::env::var_os("ASI_LOG_JSON") {
+        match std::fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                logger.format(json_log::write_record)
+                    .target(env_logger::Target::Pipe(Box::new(file)));
+            },
+            Err(err) => {
+                eprintln!("Failed to open JSON log '{}': {}", path.to_string_lossy(), err);
+                std::process::exit(-1);
+            },
+        }
+    }
+    logger.init();
 
     let mut control = match UdsControlServer::start("asi.sock", true) {
         Ok(server) => server,
@@ -131,7 +510,81 @@ fn main() {
         },
     };
 
-    let mut host = AsiBasicHost::new();
+    // Host settings come from a config file, processes are unlimited without one.
+    let config = match std::env::var_os("ASI_HOST_CONFIG") {
+        Some(path) => match HostConfig::from_file(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Failed to read host config '{}': {}", path.to_string_lossy(), err);
+                std::process::exit(-1);
+            },
+        },
+        None => HostConfig::default(),
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all().thread_name("asi-runtime");
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            log::error!("Failed to start runtime: {}", err);
+            std::process::exit(-1);
+        },
+    };
+    let runtime_handle = runtime.handle().clone();
+    let _runtime = runtime_handle.enter();
+
+    // Guest lookups use the system resolver unless a static hosts table is provided.
+    let resolver = match std::env::var_os("ASI_HOSTS_TABLE") {
+        Some(path) => Resolver::from_hosts_file(&path),
+        None => Resolver::system().map_err(anyhow::Error::from),
+    };
+    let resolver = match resolver {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            log::error!("Failed to create resolver: {}", err);
+            std::process::exit(-1);
+        },
+    };
+
+    let dispatch = match asi_sysreq::standard_dispatch_table() {
+        Ok(dispatch) => dispatch,
+        Err(err) => {
+            log::error!("Failed to register RPC handlers: {}", err);
+            std::process::exit(-1);
+        },
+    };
+
+    // Guests log at info unless a default filter is provided.
+    let guest_log_filter = match std::env::var("ASI_GUEST_LOG") {
+        Ok(directives) => match directives.parse() {
+            Ok(filter) => filter,
+            Err(err) => {
+                log::error!("Bad guest log filter: {}", err);
+                std::process::exit(-1);
+            },
+        },
+        Err(_) => LogFilter::new(Some(LogLevel::Info)),
+    };
+
+    let registry = match ModuleRegistry::open(config.module_registry_dir()) {
+        Ok(registry) => Arc::new(registry),
+        Err(err) => {
+            log::error!("Failed to open module registry: {}", err);
+            std::process::exit(-1);
+        },
+    };
+
+    let mut host = match AsiBasicHost::new(runtime, dispatch, resolver, guest_log_filter, config) {
+        Ok(host) => host,
+        Err(err) => {
+            log::error!("Failed to create host: {}", err);
+            std::process::exit(-1);
+        },
+    };
 
     loop {
         let request = match control.wait_request() {
@@ -151,13 +604,194 @@ fn main() {
                 log::info!("Shutdown request, stopping host...");
                 break;
             },
-            ClientRequest::Run { binary } => {
+            ClientRequest::Run { binary, module, name, net_policy, output, limits, wait } => {
+                if module.is_some() && !binary.is_empty() {
+                    request.respond(Err("run takes a binary or a registered module, not both".to_string()));
+                    continue;
+                }
+
+                // Registered modules run under their registry name unless named.
+                let (binary, name) = match module {
+                    Some(module) => {
+                        let loaded = module.parse::<ModuleRef>()
+                            .and_then(|module| Ok((registry.load(&module)?, module.name)));
+                        match loaded {
+                            Ok((binary, module_name)) => (Cow::Owned(binary), Some(name.clone().unwrap_or(module_name))),
+                            Err(err) => {
+                                request.respond(Err(err.to_string()));
+                                continue;
+                            },
+                        }
+                    },
+                    None => (Cow::Borrowed(binary.as_slice()), name.clone()),
+                };
+
+                let net_policy = match net_policy {
+                    Some(policy) => match serde_json::from_slice(policy) {
+                        Ok(policy) => policy,
+                        Err(err) => {
+                            request.respond(Err(format!("bad network policy: {}", err)));
+                            continue;
+                        },
+                    },
+                    None => NetPolicy::allow_all(),
+                };
+
+                let output_route = match output.as_deref().map(str::parse).transpose() {
+                    Ok(route) => route.unwrap_or(OutputRoute::Buffer),
+                    Err(err) => {
+                        request.respond(Err(err.to_string()));
+                        continue;
+                    },
+                };
+
+                let limits = match limits {
+                    Some(limits) => match serde_json::from_slice(limits) {
+                        Ok(limits) => limits,
+                        Err(err) => {
+                            request.respond(Err(format!("bad resource limits: {}", err)));
+                            continue;
+                        },
+                    },
+                    None => ResourceLimits::default(),
+                };
+
                 println!("Starting remote module...");
-                if let Err(err) = host.spawn_process_data(binary) {
-                    println!("Failed to start process: {}", err);
-                    request.respond(Err("failed to start process".to_string()));
-                } else {
-                    request.respond(Ok(vec![]));
+                let wait = *wait;
+                let spawned = host.spawn_process_data(binary.into_owned(), name, net_policy, output_route, limits);
+
+                // Compilation and instantiation complete on the runtime, the control loop
+                // keeps serving other clients meanwhile.
+                runtime_handle.spawn(async move {
+                    match spawned.await {
+                        Ok(process) if wait => {
+                            // The pid goes out first, the final state once the process terminates.
+                            let (sender, stream) = mpsc::channel();
+                            let _ = sender.send(process.pid.to_le_bytes().to_vec());
+                            process.notify_exit(sender);
+                            request.respond_stream(stream);
+                        },
+                        Ok(process) => request.respond(Ok(process.pid.to_le_bytes().to_vec())),
+                        Err(err) => {
+                            println!("Failed to start process: {:#}", err);
+                            request.respond(Err(format!("failed to start process: {:#}", err)));
+                        },
+                    }
+                });
+            },
+            ClientRequest::List => {
+                match serde_json::to_vec(&host.list()) {
+                    Ok(list) => request.respond(Ok(list)),
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::Kill { pid } => {
+                match host.kill(*pid) {
+                    Ok(stopped) => {
+                        runtime_handle.spawn(async move {
+                            match stopped.await {
+                                Ok(()) => request.respond(Ok(vec![])),
+                                Err(err) => request.respond(Err(err.to_string())),
+                            }
+                        });
+                    },
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::Upload { name, binary } => {
+                if let Err(err) = ModuleRegistry::check_name(name) {
+                    request.respond(Err(err.to_string()));
+                    continue;
+                }
+
+                // Uploads are compiled, which rejects invalid modules and spares their
+                // first run the compilation. Both happen off the control loop.
+                let (modules, registry) = (host.module_cache().clone(), registry.clone());
+                let (name, binary) = (name.clone(), binary.clone());
+                runtime_handle.spawn_blocking(move || {
+                    if let Err(err) = ModuleCache::get_or_compile(&modules, &binary) {
+                        request.respond(Err(format!("invalid module: {}", err)));
+                        return;
+                    }
+
+                    match registry.upload(&name, &binary) {
+                        Ok(version) => {
+                            log::info!("Module {}@{} uploaded", name, version);
+                            request.respond(Ok(version.to_le_bytes().to_vec()));
+                        },
+                        Err(err) => request.respond(Err(err.to_string())),
+                    }
+                });
+            },
+            ClientRequest::ListModules => {
+                let list = registry.list()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|modules| Ok(serde_json::to_vec(&modules)?));
+
+                match list {
+                    Ok(list) => request.respond(Ok(list)),
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::RemoveModule { module } => {
+                let removed = module.parse::<ModuleRef>()
+                    .and_then(|module| registry.remove(&module));
+
+                match removed {
+                    Ok(removed) => request.respond(Ok((removed as u32).to_le_bytes().to_vec())),
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::CacheStats => {
+                let stats = host.module_cache().lock().expect("module cache lock poisoned").stats();
+                match serde_json::to_vec(&stats) {
+                    Ok(stats) => request.respond(Ok(stats)),
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::ClearCache => {
+                let cleared = host.module_cache().lock().expect("module cache lock poisoned").clear();
+                match cleared {
+                    Ok(()) => request.respond(Ok(vec![])),
+                    Err(err) => request.respond(Err(format!("failed to clear module cache: {}", err))),
+                }
+            },
+            ClientRequest::SetLogFilter { pid, filter } => {
+                let result = filter.parse()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|filter| host.set_log_filter(*pid, filter));
+
+                match result {
+                    Ok(()) => request.respond(Ok(vec![])),
+                    Err(err) => request.respond(Err(err.to_string())),
+                }
+            },
+            ClientRequest::Logs { pid, follow, filter } => {
+                let filter = match filter.as_deref().map(str::parse).transpose() {
+                    Ok(filter) => filter.unwrap_or(LogFilter::new(Some(LogLevel::Trace))),
+                    Err(err) => {
+                        request.respond(Err(err.to_string()));
+                        continue;
+                    },
+                };
+
+                let pid = *pid;
+                match host.process_log(pid) {
+                    Some(log) => {
+                        let stream = log.stream(filter, *follow);
+                        request.respond_stream(stream);
+                    },
+                    None => request.respond(Err(format!("no process {}", pid))),
+                }
+            },
+            ClientRequest::Attach { pid } => {
+                let pid = *pid;
+                match host.process_output(pid) {
+                    Some(output) => {
+                        let stream = output.attach();
+                        request.respond_stream(stream);
+                    },
+                    None => request.respond(Err(format!("no process {}", pid))),
                 }
             },
         }
@@ -165,6 +799,8 @@ fn main() {
 
     control.shutdown();
 
+    host.broadcast(HostEvent::Shutdown);
+
     /*for module in std::env::args_os().skip(1) {
         println!("Starting module '{}'...", module.to_string_lossy());
 
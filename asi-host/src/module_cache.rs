@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::Mutex};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, Module};
+
+/// Cache of compiled modules, keyed by the SHA-256 of their binary.
+///
+/// Compiled modules are kept in memory and serialized to a directory, so a
+/// binary is only compiled once, even across host restarts.
+pub struct ModuleCache {
+    engine: Engine,
+    dir: PathBuf,
+    modules: HashMap<[u8; 32], Module>,
+    memory_hits: u64,
+    disk_hits: u64,
+    misses: u64,
+}
+
+/// Cache statistics sent to control clients, as JSON.
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    /// Modules held in memory.
+    pub memory_entries: usize,
+    /// Modules serialized to the cache directory.
+    pub disk_entries: usize,
+    /// Size of the serialized modules.
+    pub disk_bytes: u64,
+    /// Lookups served from memory since the host started.
+    pub memory_hits: u64,
+    /// Lookups served from the cache directory since the host started.
+    pub disk_hits: u64,
+    /// Lookups that compiled the module since the host started.
+    pub misses: u64,
+}
+
+impl ModuleCache {
+    /// Extension of serialized modules in the cache directory.
+    const EXTENSION: &'static str = "cwasm";
+
+    /// Open the cache in `dir`, creating it if needed.
+    pub fn open(engine: Engine, dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            engine,
+            dir: dir.as_ref().to_path_buf(),
+            modules: HashMap::new(),
+            memory_hits: 0,
+            disk_hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Compiled module of `binary`, compiled and cached if not seen before.
+    ///
+    /// The cache is only locked to look the module up and to store it, other
+    /// users of the cache are not held up by a compilation.
+    pub fn get_or_compile(cache: &Mutex<Self>, binary: &[u8]) -> anyhow::Result<Module> {
+        let hash: [u8; 32] = Sha256::digest(binary).into();
+        let (engine, path) = {
+            let mut cache = cache.lock().expect("module cache lock poisoned");
+            if let Some(module) = cache.lookup(&hash) {
+                return Ok(module);
+            }
+            (cache.engine.clone(), cache.entry_path(&hash))
+        };
+
+        let module = Module::from_binary(&engine, binary)?;
+
+        // A module that could not be written out is still cached in memory.
+        if let Err(err) = Self::write_entry(&path, &module) {
+            log::warn!("Failed to write cached module '{}': {}", path.to_string_lossy(), err);
+        }
+
+        let mut cache = cache.lock().expect("module cache lock poisoned");
+        cache.misses += 1;
+        cache.modules.insert(hash, module.clone());
+        Ok(module)
+    }
+
+    /// Module with `hash`, from memory or the cache directory.
+    fn lookup(&mut self, hash: &[u8; 32]) -> Option<Module> {
+        if let Some(module) = self.modules.get(hash) {
+            self.memory_hits += 1;
+            return Some(module.clone());
+        }
+
+        let path = self.entry_path(hash);
+        if path.exists() {
+            // SAFETY: the cache directory only holds modules serialized by the host,
+            // and wasmtime rejects those built by another version or engine config.
+            match unsafe { Module::deserialize_file(&self.engine, &path) } {
+                Ok(module) => {
+                    self.disk_hits += 1;
+                    self.modules.insert(*hash, module.clone());
+                    return Some(module);
+                },
+                Err(err) => log::warn!("Discarding cached module '{}': {}", path.to_string_lossy(), err),
+            }
+        }
+        None
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (disk_entries, disk_bytes) = self.disk_entries()
+            .map(|entries| entries.fold((0, 0), |(count, bytes), (_, len)| (count + 1, bytes + len)))
+            .unwrap_or_default();
+
+        CacheStats {
+            memory_entries: self.modules.len(),
+            disk_entries,
+            disk_bytes,
+            memory_hits: self.memory_hits,
+            disk_hits: self.disk_hits,
+            misses: self.misses,
+        }
+    }
+
+    /// Drop every cached module, in memory and on disk. Running processes keep
+    /// their module.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.modules.clear();
+        for (path, _) in self.disk_entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, hash: &[u8; 32]) -> PathBuf {
+        let name: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(name).with_extension(Self::EXTENSION)
+    }
+
+    /// Write `module` to `path` through a temporary file, so a crash never leaves
+    /// a truncated entry behind.
+    fn write_entry(path: &Path, module: &Module) -> anyhow::Result<()> {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, module.serialize()?)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Paths and sizes of the serialized modules.
+    fn disk_entries(&self) -> io::Result<impl Iterator<Item = (PathBuf, u64)>> {
+        let entries = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == Self::EXTENSION))
+            .filter_map(|path| {
+                let len = fs::metadata(&path).ok()?.len();
+                Some((path, len))
+            });
+        Ok(entries)
+    }
+}
@@ -0,0 +1,273 @@
+use std::{fs, io, path::{Path, PathBuf}, str::FromStr, sync::Mutex, time::{Duration, UNIX_EPOCH}};
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Modules uploaded to the host, stored by name and version.
+///
+/// Each upload of a name adds a version, numbered from 1. Versions live in
+/// `<dir>/<name>/<version>.wasm`, and the last version given to a name in
+/// `<dir>/<name>/latest` so versions are never reused once removed.
+pub struct ModuleRegistry {
+    dir: PathBuf,
+    /// Held while versions are added or removed, uploads complete concurrently.
+    lock: Mutex<()>,
+}
+
+/// Registered module version sent to control clients, as JSON.
+#[derive(Serialize, Debug)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub version: u32,
+    /// Size of the binary.
+    pub size_bytes: u64,
+    /// Upload time, in milliseconds since the Unix epoch.
+    pub uploaded_ms: u64,
+}
+
+/// Reference to a registered module, `<name>` for its latest version or
+/// `<name>@<version>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleRef {
+    pub name: String,
+    pub version: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("bad module name '{0}', names are letters, digits, '-', '_' and '.'")]
+    BadName(String),
+    #[error("bad module version '{0}'")]
+    BadVersion(String),
+    #[error("no module '{0}'")]
+    NotFound(String),
+    #[error("registry IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl FromStr for ModuleRef {
+    type Err = RegistryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => {
+                let version = version.parse().map_err(|_| RegistryError::BadVersion(version.to_string()))?;
+                (name, Some(version))
+            },
+            None => (s, None),
+        };
+        ModuleRegistry::check_name(name)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            version,
+        })
+    }
+}
+
+impl std::fmt::Display for ModuleRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "{}@{}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl ModuleRegistry {
+    /// Extension of module binaries in the registry.
+    const EXTENSION: &'static str = "wasm";
+    /// File holding the last version given to a name.
+    const LATEST: &'static str = "latest";
+
+    /// Open the registry in `dir`, creating it if needed.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Store `binary` as the next version of `name`, returning the version.
+    ///
+    /// The binary is stored as is, callers validate it first.
+    pub fn upload(&self, name: &str, binary: &[u8]) -> Result<u32, RegistryError> {
+        Self::check_name(name)?;
+
+        let _lock = self.lock.lock().expect("module registry lock poisoned");
+        fs::create_dir_all(self.dir.join(name))?;
+        // Names uploaded before versions were counted have no counter, their latest version stands in.
+        let latest = self.latest(name)?.max(self.versions(name)?.last().copied().unwrap_or(0));
+        let version = latest + 1;
+
+        // The counter is written first, a crash in between skips a version rather than reusing one.
+        Self::write_file(&self.dir.join(name).join(Self::LATEST), version.to_string().as_bytes())?;
+        Self::write_file(&self.version_path(name, version), binary)?;
+        Ok(version)
+    }
+
+    /// Binary of `module`.
+    pub fn load(&self, module: &ModuleRef) -> Result<Vec<u8>, RegistryError> {
+        let version = match module.version {
+            Some(version) => version,
+            None => *self.versions(&module.name)?.last().ok_or_else(|| RegistryError::NotFound(module.to_string()))?,
+        };
+
+        match fs::read(self.version_path(&module.name, version)) {
+            Ok(binary) => Ok(binary),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(RegistryError::NotFound(module.to_string())),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Every registered version, by name then version.
+    pub fn list(&self) -> Result<Vec<ModuleInfo>, RegistryError> {
+        let mut names: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        names.sort();
+
+        let mut modules = vec![];
+        for name in names {
+            for version in self.versions(&name)? {
+                let Ok(metadata) = fs::metadata(self.version_path(&name, version)) else {
+                    continue;
+                };
+                let uploaded = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+                modules.push(ModuleInfo {
+                    name: name.clone(),
+                    version,
+                    size_bytes: metadata.len(),
+                    uploaded_ms: uploaded.unwrap_or(Duration::ZERO).as_millis() as u64,
+                });
+            }
+        }
+        Ok(modules)
+    }
+
+    /// Remove `module`, every version of it if no version is given. Returns the
+    /// number of versions removed.
+    pub fn remove(&self, module: &ModuleRef) -> Result<usize, RegistryError> {
+        let _lock = self.lock.lock().expect("module registry lock poisoned");
+        let versions = match module.version {
+            Some(version) => vec![version],
+            None => self.versions(&module.name)?,
+        };
+
+        let mut removed = 0;
+        for version in versions {
+            match fs::remove_file(self.version_path(&module.name, version)) {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if removed == 0 {
+            return Err(RegistryError::NotFound(module.to_string()));
+        }
+
+        // The name's directory is kept with its counter, a later upload continues after the removed versions.
+        Ok(removed)
+    }
+
+    /// Last version given to `name`, 0 if none.
+    fn latest(&self, name: &str) -> Result<u32, RegistryError> {
+        let path = self.dir.join(name).join(Self::LATEST);
+        match fs::read_to_string(&path) {
+            Ok(latest) => latest.trim().parse().map_err(|_| RegistryError::BadVersion(latest)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Versions of `name`, oldest first.
+    fn versions(&self, name: &str) -> Result<Vec<u32>, RegistryError> {
+        let entries = match fs::read_dir(self.dir.join(name)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut versions: Vec<u32> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == Self::EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    fn version_path(&self, name: &str, version: u32) -> PathBuf {
+        self.dir.join(name).join(version.to_string()).with_extension(Self::EXTENSION)
+    }
+
+    /// Write `contents` to `path` through a temporary file, so a crash never
+    /// leaves a truncated file behind.
+    fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Names become directory names, so they are kept to a safe set of characters.
+    pub fn check_name(name: &str) -> Result<(), RegistryError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(RegistryError::BadName(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(test: &str) -> ModuleRegistry {
+        let dir = std::env::temp_dir().join(format!("asi-registry-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ModuleRegistry::open(dir).unwrap()
+    }
+
+    #[test]
+    fn upload_numbers_versions() {
+        let registry = registry("upload");
+        assert_eq!(registry.upload("app", b"a").unwrap(), 1);
+        assert_eq!(registry.upload("app", b"b").unwrap(), 2);
+        assert_eq!(registry.upload("other", b"c").unwrap(), 1);
+
+        assert_eq!(registry.load(&"app".parse().unwrap()).unwrap(), b"b");
+        assert_eq!(registry.load(&"app@1".parse().unwrap()).unwrap(), b"a");
+        assert_eq!(registry.list().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn removed_versions_are_not_reused() {
+        let registry = registry("reuse");
+        registry.upload("app", b"a").unwrap();
+        registry.upload("app", b"b").unwrap();
+
+        assert_eq!(registry.remove(&"app@2".parse().unwrap()).unwrap(), 1);
+        assert_eq!(registry.upload("app", b"c").unwrap(), 3);
+
+        assert_eq!(registry.remove(&"app".parse().unwrap()).unwrap(), 2);
+        assert!(registry.list().unwrap().is_empty());
+        assert!(matches!(registry.load(&"app".parse().unwrap()), Err(RegistryError::NotFound(_))));
+        assert_eq!(registry.upload("app", b"d").unwrap(), 4);
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!("../x".parse::<ModuleRef>().is_err());
+        assert!(".hidden".parse::<ModuleRef>().is_err());
+        assert!("app@x".parse::<ModuleRef>().is_err());
+        assert_eq!("app@3".parse::<ModuleRef>().unwrap(), ModuleRef { name: "app".to_string(), version: Some(3) });
+    }
+}
@@ -0,0 +1,380 @@
+use std::{fmt, net::{IpAddr, SocketAddr}, ops::RangeInclusive, str::FromStr};
+
+use serde::{Serialize, Deserialize};
+
+/// Network operation a guest is attempting.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetOp {
+    Connect,
+    Bind,
+    Lookup,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetAction {
+    Allow,
+    Deny,
+}
+
+/// What a network operation is directed at.
+#[derive(Debug, Clone, Copy)]
+pub enum NetTarget<'a> {
+    Addr(SocketAddr),
+    Host {
+        name: &'a str,
+        port: Option<u16>,
+    },
+}
+
+impl<'a> NetTarget<'a> {
+    /// Target of a lookup of `name`, literal addresses are targeted as addresses
+    /// so they are subject to address rules.
+    pub fn lookup(name: &'a str, port: Option<u16>) -> Self {
+        match name.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => NetTarget::Addr(SocketAddr::new(ip, port.unwrap_or(0))),
+            Err(_) => NetTarget::Host { name, port },
+        }
+    }
+}
+
+impl<'a> fmt::Display for NetTarget<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetTarget::Addr(addr) => write!(f, "{}", addr),
+            NetTarget::Host { name, port: Some(port) } => write!(f, "{}:{}", name, port),
+            NetTarget::Host { name, port: None } => write!(f, "{}", name),
+        }
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses reach IPv4 hosts, so they are matched as IPv4.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                Self::prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                Self::prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            },
+            _ => false,
+        }
+    }
+
+    fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+        let full_bytes = prefix as usize / 8;
+        let rem_bits = prefix % 8;
+        if net[..full_bytes] != ip[..full_bytes] {
+            return false;
+        }
+        if rem_bits == 0 {
+            return true;
+        }
+        let mask = 0xffu8 << (8 - rem_bits);
+        net[full_bytes] & mask == ip[full_bytes] & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| format!("bad CIDR address '{}'", addr))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| format!("bad CIDR prefix '{}'", prefix))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(format!("CIDR prefix {} out of range", prefix));
+        }
+
+        // Networks within the IPv4-mapped range are kept as IPv4, as addresses are
+        // matched.
+        if let IpAddr::V6(v6) = addr {
+            if let (Some(v4), true) = (v6.to_ipv4_mapped(), prefix >= 96) {
+                return Ok(Self { addr: IpAddr::V4(v4), prefix: prefix - 96 });
+            }
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        format!("{}/{}", cidr.addr, cidr.prefix)
+    }
+}
+
+/// A single policy rule. Every criterion that is set must match for the rule
+/// to apply. Address criteria (`cidr`) never match hostname targets and
+/// hostname criteria (`host`) never match address targets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetRule {
+    pub action: NetAction,
+
+    /// Operations the rule applies to, all operations if empty.
+    #[serde(default)]
+    pub ops: Vec<NetOp>,
+
+    #[serde(default)]
+    pub cidr: Option<Cidr>,
+
+    #[serde(default)]
+    pub ports: Option<RangeInclusive<u16>>,
+
+    /// Hostname pattern, either an exact name or `*.` followed by a domain
+    /// suffix.
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+impl NetRule {
+    fn matches(&self, op: NetOp, target: &NetTarget) -> bool {
+        if !self.ops.is_empty() && !self.ops.contains(&op) {
+            return false;
+        }
+
+        let port = match target {
+            NetTarget::Addr(addr) => {
+                if self.host.is_some() {
+                    return false;
+                }
+                if let Some(cidr) = &self.cidr {
+                    if !cidr.contains(&addr.ip()) {
+                        return false;
+                    }
+                }
+                Some(addr.port())
+            },
+            NetTarget::Host { name, port } => {
+                if self.cidr.is_some() {
+                    return false;
+                }
+                if let Some(pattern) = &self.host {
+                    if !Self::host_matches(pattern, name) {
+                        return false;
+                    }
+                }
+                *port
+            },
+        };
+
+        match (&self.ports, port) {
+            (Some(ports), Some(port)) => ports.contains(&port),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    fn host_matches(pattern: &str, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => {
+                name.len() > suffix.len()
+                    && name.to_ascii_lowercase().ends_with(&suffix.to_ascii_lowercase())
+                    && name.as_bytes()[name.len() - suffix.len() - 1] == b'.'
+            },
+            None => name.eq_ignore_ascii_case(pattern),
+        }
+    }
+}
+
+/// Per-process network policy, evaluated first-match over `rules`, falling
+/// back to `default` when no rule matches.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetPolicy {
+    pub default: NetAction,
+
+    #[serde(default)]
+    pub rules: Vec<NetRule>,
+}
+
+impl NetPolicy {
+    /// Policy that allows all network access.
+    pub fn allow_all() -> Self {
+        Self {
+            default: NetAction::Allow,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Check whether `op` against `target` is allowed, logging denials.
+    pub fn check(&self, op: NetOp, target: NetTarget) -> bool {
+        let action = self.rules.iter()
+            .find(|rule| rule.matches(op, &target))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default);
+
+        if action == NetAction::Deny {
+            log::warn!("Network policy denied {:?} to {}", op, target);
+            return false;
+        }
+        true
+    }
+}
+
+impl Default for NetPolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> NetTarget<'static> {
+        NetTarget::Addr(addr.parse().unwrap())
+    }
+
+    fn rule(json: &str) -> NetRule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn cidr_parses() {
+        assert_eq!(String::from("10.0.0.0/8".parse::<Cidr>().unwrap()), "10.0.0.0/8");
+        assert_eq!(String::from("fd00::/8".parse::<Cidr>().unwrap()), "fd00::/8");
+        assert_eq!(String::from("192.168.1.1".parse::<Cidr>().unwrap()), "192.168.1.1/32");
+        assert_eq!(String::from("::1".parse::<Cidr>().unwrap()), "::1/128");
+        assert_eq!(String::from("::ffff:10.0.0.0/104".parse::<Cidr>().unwrap()), "10.0.0.0/8");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!(serde_json::from_str::<Cidr>("\"10.0.0.0/40\"").is_err());
+    }
+
+    #[test]
+    fn cidr_matches() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        let net: Cidr = "192.168.0.0/23".parse().unwrap();
+        assert!(net.contains(&ip("192.168.1.255")));
+        assert!(!net.contains(&ip("192.168.2.0")));
+
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+        assert!(!net.contains(&ip("10.0.0.1")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_mapped() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("::ffff:10.0.0.1")));
+        assert!(!net.contains(&ip("::ffff:11.0.0.1")));
+
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(mapped.contains(&ip("10.0.0.1")));
+        assert!(mapped.contains(&ip("::ffff:10.0.0.1")));
+
+        let policy: NetPolicy = serde_json::from_str(r#"{
+            "default": "allow",
+            "rules": [{ "action": "deny", "cidr": "10.0.0.0/8" }]
+        }"#).unwrap();
+        assert!(!policy.check(NetOp::Connect, addr("[::ffff:10.0.0.1]:80")));
+        assert!(policy.check(NetOp::Connect, addr("[::ffff:11.0.0.1]:80")));
+    }
+
+    #[test]
+    fn port_ranges() {
+        let web = rule(r#"{ "action": "allow", "ports": { "start": 80, "end": 443 } }"#);
+        assert!(web.matches(NetOp::Connect, &addr("1.2.3.4:80")));
+        assert!(web.matches(NetOp::Connect, &addr("1.2.3.4:443")));
+        assert!(!web.matches(NetOp::Connect, &addr("1.2.3.4:79")));
+        assert!(!web.matches(NetOp::Connect, &addr("1.2.3.4:444")));
+        assert!(web.matches(NetOp::Lookup, &NetTarget::Host { name: "example.com", port: Some(80) }));
+        // Targets without a port never match a port range.
+        assert!(!web.matches(NetOp::Lookup, &NetTarget::Host { name: "example.com", port: None }));
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(NetRule::host_matches("example.com", "example.com"));
+        assert!(NetRule::host_matches("example.com", "EXAMPLE.com."));
+        assert!(!NetRule::host_matches("example.com", "www.example.com"));
+
+        assert!(NetRule::host_matches("*.example.com", "www.example.com"));
+        assert!(NetRule::host_matches("*.example.com", "a.b.Example.COM"));
+        assert!(!NetRule::host_matches("*.example.com", "example.com"));
+        assert!(!NetRule::host_matches("*.example.com", "badexample.com"));
+
+        let hosts = rule(r#"{ "action": "deny", "host": "*.internal" }"#);
+        assert!(hosts.matches(NetOp::Lookup, &NetTarget::Host { name: "db.internal", port: None }));
+        // Hostname rules never match address targets, and address rules never match hostnames.
+        assert!(!hosts.matches(NetOp::Connect, &addr("10.0.0.1:80")));
+        let addrs = rule(r#"{ "action": "deny", "cidr": "10.0.0.0/8" }"#);
+        assert!(!addrs.matches(NetOp::Lookup, &NetTarget::Host { name: "db.internal", port: None }));
+    }
+
+    #[test]
+    fn rule_ops_and_first_match() {
+        let policy: NetPolicy = serde_json::from_str(r#"{
+            "default": "deny",
+            "rules": [
+                { "action": "deny", "ops": ["bind"] },
+                { "action": "allow", "cidr": "192.168.0.0/16" }
+            ]
+        }"#).unwrap();
+        assert!(policy.check(NetOp::Connect, addr("192.168.1.1:22")));
+        assert!(!policy.check(NetOp::Bind, addr("192.168.1.1:22")));
+        assert!(!policy.check(NetOp::Connect, addr("8.8.8.8:53")));
+    }
+
+    #[test]
+    fn literal_lookup_checked_against_cidr_rules() {
+        let policy: NetPolicy = serde_json::from_str(r#"{
+            "default": "allow",
+            "rules": [
+                { "action": "deny", "cidr": "10.0.0.0/8" },
+                { "action": "deny", "host": "*.internal" }
+            ]
+        }"#).unwrap();
+
+        assert!(!policy.check(NetOp::Lookup, NetTarget::lookup("10.1.2.3", None)));
+        assert!(!policy.check(NetOp::Lookup, NetTarget::lookup("10.1.2.3", Some(80))));
+        assert!(!policy.check(NetOp::Lookup, NetTarget::lookup("[::ffff:10.1.2.3]", None)));
+        assert!(policy.check(NetOp::Lookup, NetTarget::lookup("192.0.2.1", None)));
+
+        // Names are still checked against host rules.
+        assert!(!policy.check(NetOp::Lookup, NetTarget::lookup("db.internal", None)));
+        assert!(policy.check(NetOp::Lookup, NetTarget::lookup("service.example", None)));
+    }
+}
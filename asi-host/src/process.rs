@@ -0,0 +1,216 @@
+use std::{sync::{mpsc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use libasi_interop::diagnostics::LogFilter;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::{host_events::HostEventSender, limits::LimitExceeded, process_log::ProcessLog};
+
+/// Lifecycle state of a process.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ProcessState {
+    /// Spawned, the guest has not been entered yet.
+    Starting,
+    Running,
+    /// The guest returned from its entry point or called `proc_exit`.
+    Exited {
+        code: i32,
+    },
+    /// The guest trapped or the host failed to run it.
+    Trapped {
+        message: String,
+        /// Trap code, if the guest trapped.
+        trap_code: Option<String>,
+        /// Guest stack at the trap, innermost frame first.
+        backtrace: Vec<TrapFrame>,
+    },
+    /// The guest was interrupted by a kill request.
+    Killed,
+    /// The guest was terminated for going over one of its resource limits.
+    LimitExceeded {
+        limit: LimitExceeded,
+    },
+}
+
+impl ProcessState {
+    pub fn is_terminated(&self) -> bool {
+        !matches!(self, ProcessState::Starting | ProcessState::Running)
+    }
+}
+
+/// Guest function on the stack of a trap.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub module: Option<String>,
+    pub func_index: u32,
+    /// Function name, from the module's name section.
+    pub func_name: Option<String>,
+    /// Offset of the instruction in the module.
+    pub module_offset: Option<usize>,
+}
+
+/// Error a killed guest is interrupted with.
+#[derive(Error, Debug)]
+#[error("process killed")]
+pub struct Killed;
+
+/// Resource usage of a process, updated as it runs.
+#[derive(Default)]
+pub struct ProcessStats {
+    rpc_calls: AtomicU64,
+    memory_bytes: AtomicU64,
+}
+
+impl ProcessStats {
+    pub fn count_rpc_call(&self) {
+        self.rpc_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for linear memory growing by `bytes`.
+    pub fn grow_memory(&self, bytes: usize) {
+        self.memory_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A process of the host, shared between the host, the process thread and the
+/// process's RPC handlers.
+pub struct Process {
+    pub pid: u32,
+    pub name: String,
+    pub started: SystemTime,
+    pub events: HostEventSender,
+    /// Log filter of the process, changed by the host at runtime.
+    pub log_filter: Mutex<LogFilter>,
+    /// Captured log of the process.
+    pub log: ProcessLog,
+    pub stats: ProcessStats,
+    state: Mutex<ProcessStatus>,
+    /// Set once the termination of the process is being recorded.
+    terminating: AtomicBool,
+    /// Signalled to stop the guest.
+    kill: Notify,
+}
+
+impl Process {
+    pub fn new(pid: u32, name: String, events: HostEventSender, log_filter: LogFilter, log: ProcessLog) -> Self {
+        let started = SystemTime::now();
+        Self {
+            pid,
+            name,
+            started,
+            events,
+            log_filter: Mutex::new(log_filter),
+            log,
+            stats: ProcessStats::default(),
+            state: Mutex::new(ProcessStatus {
+                state: ProcessState::Starting,
+                since: started,
+                exit_waiters: Vec::new(),
+            }),
+            terminating: AtomicBool::new(false),
+            kill: Notify::new(),
+        }
+    }
+
+    /// Ask for the guest to be stopped, see [`Process::killed`].
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+
+    /// Complete once the process is killed, even if it was killed before
+    /// this was awaited.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state.lock().expect("process state lock poisoned").state.clone()
+    }
+
+    /// Move the process to `state`, a terminated process keeps its final state.
+    pub fn set_state(&self, state: ProcessState) {
+        let mut status = self.state.lock().expect("process state lock poisoned");
+        if status.state.is_terminated() {
+            return;
+        }
+        status.state = state;
+        status.since = SystemTime::now();
+
+        if status.state.is_terminated() {
+            for waiter in std::mem::take(&mut status.exit_waiters) {
+                let _ = waiter.send(status.exit_report());
+            }
+        }
+    }
+
+    /// Claim the termination of the process, true for the first caller only,
+    /// which then records the final state.
+    pub fn claim_termination(&self) -> bool {
+        !self.terminating.swap(true, Ordering::SeqCst)
+    }
+
+    /// Send the final state of the process to `waiter` as JSON once it
+    /// terminates, then drop it.
+    pub fn notify_exit(&self, waiter: mpsc::Sender<Vec<u8>>) {
+        let mut status = self.state.lock().expect("process state lock poisoned");
+        if status.state.is_terminated() {
+            let _ = waiter.send(status.exit_report());
+        } else {
+            status.exit_waiters.push(waiter);
+        }
+    }
+
+    /// Snapshot of the process for listing.
+    pub fn info(&self) -> ProcessInfo {
+        let (state, since) = {
+            let status = self.state.lock().expect("process state lock poisoned");
+            (status.state.clone(), status.since)
+        };
+
+        // Terminated processes stop their clock when they terminate.
+        let until = if state.is_terminated() { since } else { SystemTime::now() };
+        let run_time = until.duration_since(self.started).unwrap_or(Duration::ZERO);
+
+        ProcessInfo {
+            pid: self.pid,
+            name: self.name.clone(),
+            state,
+            started_ms: self.started.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis() as u64,
+            run_time_ms: run_time.as_millis() as u64,
+            rpc_calls: self.stats.rpc_calls.load(Ordering::Relaxed),
+            memory_bytes: self.stats.memory_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct ProcessStatus {
+    state: ProcessState,
+    /// When the state was entered.
+    since: SystemTime,
+    /// Clients waiting for the process to terminate.
+    exit_waiters: Vec<mpsc::Sender<Vec<u8>>>,
+}
+
+impl ProcessStatus {
+    fn exit_report(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.state).expect("process state serializes")
+    }
+}
+
+/// Entry of the process table sent to control clients, as JSON.
+#[derive(Serialize, Debug)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ProcessState,
+    /// Spawn time, in milliseconds since the Unix epoch.
+    pub started_ms: u64,
+    /// Time since spawn, or until termination.
+    pub run_time_ms: u64,
+    pub rpc_calls: u64,
+    /// Size of the guest's linear memory.
+    pub memory_bytes: u64,
+}
@@ -0,0 +1,230 @@
+use std::{collections::VecDeque, sync::{mpsc::{self, TrySendError}, Arc, Mutex}, time::SystemTime};
+
+use libasi_interop::diagnostics::{LogFilter, LogLevel};
+
+use crate::process_output::OutputStream;
+
+/// Something a process logged or printed.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: SystemTime,
+    pub kind: LogEntryKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum LogEntryKind {
+    /// A log record.
+    Record {
+        level: LogLevel,
+        target: String,
+        message: String,
+    },
+
+    /// A chunk of output, as written by the process.
+    Output(OutputStream, Vec<u8>),
+}
+
+impl LogEntry {
+    /// Render the entry for a CLI client, records as a line and output verbatim.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.kind {
+            LogEntryKind::Record { level, target, message } => {
+                format!("[{:<5} {}] {}\n", level.as_str().to_ascii_uppercase(), target, message).into_bytes()
+            },
+            LogEntryKind::Output(_, bytes) => bytes.clone(),
+        }
+    }
+
+    /// Memory held by the entry.
+    fn size(&self) -> usize {
+        let data = match &self.kind {
+            LogEntryKind::Record { target, message, .. } => target.len() + message.len(),
+            LogEntryKind::Output(_, bytes) => bytes.len(),
+        };
+        std::mem::size_of::<Self>() + data
+    }
+
+    /// Whether the entry passes `filter`, output always does.
+    fn enabled(&self, filter: &LogFilter) -> bool {
+        match &self.kind {
+            LogEntryKind::Record { level, target, .. } => filter.enabled(target, *level),
+            LogEntryKind::Output(..) => true,
+        }
+    }
+}
+
+/// Bounded history of a process's log records and buffered output, with live followers.
+#[derive(Clone)]
+pub struct ProcessLog {
+    inner: Arc<Mutex<ProcessLogInner>>,
+}
+
+struct ProcessLogInner {
+    entries: VecDeque<LogEntry>,
+    /// Total size of `entries`.
+    size: usize,
+    followers: Vec<(LogFilter, mpsc::SyncSender<Vec<u8>>)>,
+    closed: bool,
+}
+
+impl ProcessLog {
+    /// Size of the entries kept, older entries are discarded.
+    const CAPACITY_BYTES: usize = 1024 * 1024;
+
+    /// Entries queued for a follower, followers that fall further behind are dropped.
+    const FOLLOWER_QUEUE_LEN: usize = 256;
+
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ProcessLogInner {
+                entries: VecDeque::new(),
+                size: 0,
+                followers: Vec::new(),
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn push(&self, kind: LogEntryKind) {
+        let entry = LogEntry {
+            timestamp: SystemTime::now(),
+            kind,
+        };
+
+        let mut inner = self.inner.lock().expect("process log lock poisoned");
+
+        // Followers that hung up or fell behind are dropped, a slow client never
+        // holds the process's output in memory.
+        inner.followers.retain(|(filter, follower)| {
+            if !entry.enabled(filter) {
+                return true;
+            }
+            match follower.try_send(entry.to_bytes()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::debug!("Dropping log follower that fell behind");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        // The newest entry is kept even if larger than the whole capacity.
+        let size = entry.size();
+        while inner.size + size > Self::CAPACITY_BYTES {
+            let Some(oldest) = inner.entries.pop_front() else {
+                break;
+            };
+            inner.size -= oldest.size();
+        }
+        inner.size += size;
+        inner.entries.push_back(entry);
+    }
+
+    /// Stream the captured entries passing `filter`, then new entries as they
+    /// arrive if `follow`ing. The stream ends once the process has exited.
+    pub fn stream(&self, filter: LogFilter, follow: bool) -> mpsc::Receiver<Vec<u8>> {
+        let mut inner = self.inner.lock().expect("process log lock poisoned");
+        let captured: Vec<u8> = inner.entries.iter()
+            .filter(|entry| entry.enabled(&filter))
+            .flat_map(LogEntry::to_bytes)
+            .collect();
+
+        // The captured entries go out as one chunk, ahead of the follower's queue.
+        let (sender, receiver) = mpsc::sync_channel(usize::from(!captured.is_empty()) + Self::FOLLOWER_QUEUE_LEN);
+        if !captured.is_empty() {
+            let _ = sender.send(captured);
+        }
+
+        if follow && !inner.closed {
+            inner.followers.push((filter, sender));
+        }
+        receiver
+    }
+
+    /// Mark the process as exited, ending every follower's stream.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().expect("process log lock poisoned");
+        inner.closed = true;
+        inner.followers.clear();
+    }
+}
+
+impl Default for ProcessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(bytes: &[u8]) -> LogEntryKind {
+        LogEntryKind::Output(OutputStream::Stdout, bytes.to_vec())
+    }
+
+    fn all() -> LogFilter {
+        LogFilter::new(Some(LogLevel::Trace))
+    }
+
+    #[test]
+    fn capacity_is_bytes() {
+        let log = ProcessLog::new();
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..64 {
+            log.push(output(&chunk));
+        }
+
+        let inner = log.inner.lock().unwrap();
+        assert!(inner.size <= ProcessLog::CAPACITY_BYTES);
+        assert_eq!(inner.size, inner.entries.iter().map(LogEntry::size).sum::<usize>());
+        assert!(inner.entries.len() < 64);
+    }
+
+    #[test]
+    fn oversized_entry_is_kept() {
+        let log = ProcessLog::new();
+        log.push(output(b"small"));
+        log.push(output(&vec![b'x'; ProcessLog::CAPACITY_BYTES * 2]));
+
+        let inner = log.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), 1);
+    }
+
+    #[test]
+    fn captured_entries_are_one_chunk() {
+        let log = ProcessLog::new();
+        log.push(output(b"a"));
+        log.push(LogEntryKind::Record { level: LogLevel::Debug, target: "app".to_string(), message: "hidden".to_string() });
+        log.push(output(b"b"));
+        log.close();
+
+        let stream = log.stream("info".parse().unwrap(), false);
+        assert_eq!(stream.iter().collect::<Vec<_>>(), vec![b"ab".to_vec()]);
+    }
+
+    #[test]
+    fn slow_follower_is_dropped() {
+        let log = ProcessLog::new();
+        let stream = log.stream(all(), true);
+        for _ in 0..=ProcessLog::FOLLOWER_QUEUE_LEN {
+            log.push(output(b"x"));
+        }
+
+        assert!(log.inner.lock().unwrap().followers.is_empty());
+        assert_eq!(stream.iter().count(), ProcessLog::FOLLOWER_QUEUE_LEN);
+    }
+
+    #[test]
+    fn follower_keeps_up() {
+        let log = ProcessLog::new();
+        let stream = log.stream(all(), true);
+        for _ in 0..ProcessLog::FOLLOWER_QUEUE_LEN * 2 {
+            log.push(output(b"x"));
+            assert_eq!(stream.recv().unwrap(), b"x");
+        }
+        log.close();
+        assert!(stream.recv().is_err());
+    }
+}
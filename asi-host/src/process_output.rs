@@ -0,0 +1,325 @@
+use std::{fs::File, io::{self, Write}, path::{Component, Path, PathBuf}, str::FromStr, sync::{mpsc::{self, TrySendError}, Arc, Mutex}};
+
+use wasi_common::{WasiFile, file::FileType, Error};
+
+use crate::process_log::{ProcessLog, LogEntryKind};
+
+/// Output stream of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout = 1,
+    Stderr = 2,
+}
+
+/// Where the output of a process is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputRoute {
+    /// The process's log ring buffer, see [`ProcessLog`].
+    Buffer,
+
+    /// Appended to files in the host's output directory, one per stream, named
+    /// after the path, the pid and the stream, e.g. `out.log.7.stdout`. The
+    /// path is relative and never leaves the output directory.
+    File(PathBuf),
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid output route '{0}', expected 'buffer' or 'file:<path>' with a relative path")]
+pub struct OutputRouteParseError(String);
+
+impl FromStr for OutputRoute {
+    type Err = OutputRouteParseError;
+
+    fn from_str(route: &str) -> Result<Self, Self::Err> {
+        match route.split_once(':') {
+            None if route == "buffer" => Ok(OutputRoute::Buffer),
+            Some(("file", path)) if Self::contained(Path::new(path)) => Ok(OutputRoute::File(path.into())),
+            _ => Err(OutputRouteParseError(route.to_string())),
+        }
+    }
+}
+
+impl OutputRoute {
+    /// Whether `path` names a file below the directory it is relative to.
+    fn contained(path: &Path) -> bool {
+        path.file_name().is_some() && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    }
+}
+
+/// Output of a process, delivered to its route and to every attached client.
+///
+/// Output is kept as raw bytes. Attached clients receive it as frames of the
+/// stream (1 for stdout, 2 for stderr), the little-endian length and the bytes.
+///
+/// Files are written by a thread of their own, a guest writing faster than
+/// its files take the output waits without holding up the runtime.
+#[derive(Clone)]
+pub struct ProcessOutput {
+    inner: Arc<Mutex<ProcessOutputInner>>,
+}
+
+struct ProcessOutputInner {
+    pid: u32,
+    sink: OutputSink,
+    attached: Vec<mpsc::SyncSender<Vec<u8>>>,
+    closed: bool,
+}
+
+enum OutputSink {
+    Buffer(ProcessLog),
+    File(FileQueue),
+}
+
+/// Queue of the thread writing a process's output files.
+type FileQueue = tokio::sync::mpsc::Sender<(OutputStream, Vec<u8>)>;
+
+impl OutputStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+impl ProcessOutput {
+    /// Frames queued for an attached client, clients that fall further behind are detached.
+    const ATTACH_QUEUE_LEN: usize = 256;
+
+    /// Writes queued for the file writer, guests writing more wait for it.
+    const FILE_QUEUE_LEN: usize = 64;
+
+    /// Output of process `pid`, file routes are relative to `output_dir`.
+    pub fn new(pid: u32, route: &OutputRoute, output_dir: &Path, log: ProcessLog) -> io::Result<Self> {
+        let sink = match route {
+            OutputRoute::Buffer => OutputSink::Buffer(log),
+            OutputRoute::File(path) => {
+                let path = output_dir.join(path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let stdout = Self::open_file(&path, pid, OutputStream::Stdout)?;
+                let stderr = Self::open_file(&path, pid, OutputStream::Stderr)?;
+                OutputSink::File(Self::spawn_writer(pid, stdout, stderr)?)
+            },
+        };
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(ProcessOutputInner {
+                pid,
+                sink,
+                attached: Vec::new(),
+                closed: false,
+            })),
+        })
+    }
+
+    fn open_file(path: &Path, pid: u32, stream: OutputStream) -> io::Result<File> {
+        let mut path = path.as_os_str().to_owned();
+        path.push(format!(".{}.{}", pid, stream.as_str()));
+        File::options().create(true).append(true).open(path)
+    }
+
+    /// Start the thread writing the files, it ends once the output is dropped.
+    fn spawn_writer(pid: u32, mut stdout: File, mut stderr: File) -> io::Result<FileQueue> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<(OutputStream, Vec<u8>)>(Self::FILE_QUEUE_LEN);
+        std::thread::Builder::new()
+            .name(format!("asi-output-{}", pid))
+            .spawn(move || {
+                while let Some((stream, bytes)) = receiver.blocking_recv() {
+                    let file = match stream {
+                        OutputStream::Stdout => &mut stdout,
+                        OutputStream::Stderr => &mut stderr,
+                    };
+                    if let Err(err) = file.write_all(&bytes) {
+                        log::warn!("Failed to write {} of process {}: {}", stream.as_str(), pid, err);
+                    }
+                }
+            })?;
+        Ok(sender)
+    }
+
+    /// WASI file writing to `stream` of this output.
+    pub fn handler(&self, stream: OutputStream) -> Box<dyn WasiFile> {
+        Box::new(OutputHandler {
+            output: self.clone(),
+            stream,
+        })
+    }
+
+    pub async fn write(&self, stream: OutputStream, bytes: &[u8]) {
+        let (pid, writer) = self.deliver(stream, bytes);
+        if let Some(writer) = writer {
+            if writer.send((stream, bytes.to_vec())).await.is_err() {
+                log::warn!("Output writer of process {} is gone, dropping {}", pid, stream.as_str());
+            }
+        }
+    }
+
+    /// Deliver `bytes` to the buffer and the attached clients, returning the
+    /// file writer's queue if the output goes to files.
+    fn deliver(&self, stream: OutputStream, bytes: &[u8]) -> (u32, Option<FileQueue>) {
+        let mut inner = self.inner.lock().expect("process output lock poisoned");
+        let inner = &mut *inner;
+
+        let writer = match &inner.sink {
+            OutputSink::Buffer(log) => {
+                log.push(LogEntryKind::Output(stream, bytes.to_vec()));
+                None
+            },
+            OutputSink::File(writer) => Some(writer.clone()),
+        };
+
+        if !inner.attached.is_empty() {
+            let mut frame = vec![stream as u8];
+            frame.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            frame.extend_from_slice(bytes);
+
+            // Clients that hung up or fell behind are dropped, a slow client never
+            // holds the process's output in memory.
+            inner.attached.retain(|client| match client.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::debug!("Detaching client of process {} that fell behind", inner.pid);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+
+        (inner.pid, writer)
+    }
+
+    /// Attach a client, which receives output frames until the process exits
+    /// or it falls behind.
+    pub fn attach(&self) -> mpsc::Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::sync_channel(Self::ATTACH_QUEUE_LEN);
+
+        let mut inner = self.inner.lock().expect("process output lock poisoned");
+        if !inner.closed {
+            inner.attached.push(sender);
+        }
+        receiver
+    }
+
+    /// Mark the process as exited, detaching every client.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().expect("process output lock poisoned");
+        inner.closed = true;
+        inner.attached.clear();
+    }
+}
+
+/// Guest stdout or stderr.
+struct OutputHandler {
+    output: ProcessOutput,
+    stream: OutputStream,
+}
+
+#[async_trait::async_trait]
+impl WasiFile for OutputHandler {
+    fn as_any(&self) ->  &dyn std::any::Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::CharacterDevice)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let mut len = 0;
+        for buf in bufs {
+            self.output.write(self.stream, buf).await;
+            len += buf.len();
+        }
+        Ok(len as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libasi_interop::diagnostics::{LogFilter, LogLevel};
+
+    use super::*;
+
+    fn output_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("asi-output-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn route_parses() {
+        assert_eq!("buffer".parse::<OutputRoute>().unwrap(), OutputRoute::Buffer);
+        assert_eq!("file:out.log".parse::<OutputRoute>().unwrap(), OutputRoute::File("out.log".into()));
+        assert_eq!("file:app/out.log".parse::<OutputRoute>().unwrap(), OutputRoute::File("app/out.log".into()));
+
+        // File routes stay within the output directory.
+        assert!("file:/etc/passwd".parse::<OutputRoute>().is_err());
+        assert!("file:../out.log".parse::<OutputRoute>().is_err());
+        assert!("file:app/../../out.log".parse::<OutputRoute>().is_err());
+        assert!("file:".parse::<OutputRoute>().is_err());
+        assert!("file:.".parse::<OutputRoute>().is_err());
+        assert!("stdout".parse::<OutputRoute>().is_err());
+    }
+
+    #[tokio::test]
+    async fn buffer_route_keeps_output() {
+        let log = ProcessLog::new();
+        let output = ProcessOutput::new(7, &OutputRoute::Buffer, &output_dir("buffer"), log.clone()).unwrap();
+        output.write(OutputStream::Stdout, b"out").await;
+        output.write(OutputStream::Stderr, b"err").await;
+
+        let stream = log.stream(LogFilter::new(Some(LogLevel::Trace)), false);
+        assert_eq!(stream.recv().unwrap(), b"outerr");
+    }
+
+    #[tokio::test]
+    async fn file_route_writes_under_output_dir() {
+        let dir = output_dir("file");
+        let output = ProcessOutput::new(7, &"file:app/out.log".parse().unwrap(), &dir, ProcessLog::new()).unwrap();
+        output.write(OutputStream::Stdout, b"out").await;
+        output.write(OutputStream::Stderr, b"err").await;
+
+        // The writer thread writes the files once it gets to the queued output.
+        let read = |stream: &str| std::fs::read(dir.join(format!("app/out.log.7.{}", stream))).unwrap();
+        for _ in 0..100 {
+            if read("stderr") == b"err" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read("stdout"), b"out");
+        assert_eq!(read("stderr"), b"err");
+    }
+
+    #[tokio::test]
+    async fn attached_clients_receive_frames() {
+        let output = ProcessOutput::new(7, &OutputRoute::Buffer, &output_dir("attach"), ProcessLog::new()).unwrap();
+        let client = output.attach();
+        output.write(OutputStream::Stdout, b"out").await;
+        output.write(OutputStream::Stderr, b"").await;
+
+        assert_eq!(client.recv().unwrap(), [1, 3, 0, 0, 0, b'o', b'u', b't']);
+        assert_eq!(client.recv().unwrap(), [2, 0, 0, 0, 0]);
+
+        // Clients are detached once the process exits.
+        output.close();
+        assert!(client.recv().is_err());
+        assert!(output.attach().recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_detached() {
+        let output = ProcessOutput::new(7, &OutputRoute::Buffer, &output_dir("slow"), ProcessLog::new()).unwrap();
+        let client = output.attach();
+        for _ in 0..=ProcessOutput::ATTACH_QUEUE_LEN {
+            output.write(OutputStream::Stdout, b"x").await;
+        }
+
+        assert!(output.inner.lock().unwrap().attached.is_empty());
+        assert_eq!(client.iter().count(), ProcessOutput::ATTACH_QUEUE_LEN);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, path::Path};
+
+use libasi_interop::net::{AddressFamily, DnsRecord, NetError, RecordType};
+use serde::{Serialize, Deserialize};
+use trust_dns_resolver::{TokioAsyncResolver, error::{ResolveError, ResolveErrorKind}};
+
+/// Records for a single name in a static hosts table.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HostsEntry {
+    #[serde(default)]
+    pub addrs: Vec<IpAddr>,
+
+    #[serde(default)]
+    pub srv: Vec<SrvEntry>,
+
+    #[serde(default)]
+    pub txt: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SrvEntry {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Name resolver used to service guest lookups.
+pub enum Resolver {
+    /// Resolve with the host's system DNS configuration.
+    System(Box<TokioAsyncResolver>),
+
+    /// Resolve from a fixed table of names, names not in the table do not exist.
+    Static(HashMap<String, HostsEntry>),
+}
+
+impl Resolver {
+    /// Create a resolver using the system DNS configuration.
+    pub fn system() -> Result<Self, ResolveError> {
+        Ok(Resolver::System(Box::new(TokioAsyncResolver::tokio_from_system_conf()?)))
+    }
+
+    /// Load a static resolver from a JSON file mapping names to [`HostsEntry`].
+    pub fn from_hosts_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let table: HashMap<String, HostsEntry> = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Resolver::Static(table.into_iter()
+            .map(|(name, entry)| (Self::normalize(&name), entry))
+            .collect()))
+    }
+
+    /// Resolve `hostname`, address records carry `port` (or 0).
+    pub async fn resolve(&self, hostname: &str, port: Option<u16>, family: AddressFamily, record_type: RecordType) -> Result<Vec<DnsRecord>, NetError> {
+        let port = port.unwrap_or(0);
+
+        // Literal addresses resolve to themselves without a query.
+        if let Ok(ip) = hostname.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return match (record_type, ip) {
+                (RecordType::Address, _) | (RecordType::A, IpAddr::V4(_)) | (RecordType::Aaaa, IpAddr::V6(_)) => {
+                    Ok(vec![DnsRecord::Addr(SocketAddr::new(ip, port))])
+                },
+                _ => Err(NetError::NotFound),
+            };
+        }
+
+        let mut records = match self {
+            Resolver::System(resolver) => Self::resolve_system(resolver, hostname, port, record_type).await?,
+            Resolver::Static(table) => Self::resolve_static(table, hostname, port, record_type)?,
+        };
+
+        match family {
+            AddressFamily::Any => (),
+            AddressFamily::PreferIpv4 => records.sort_by_key(|record| !matches!(record, DnsRecord::Addr(SocketAddr::V4(_)))),
+            AddressFamily::PreferIpv6 => records.sort_by_key(|record| !matches!(record, DnsRecord::Addr(SocketAddr::V6(_)))),
+        }
+
+        if records.is_empty() {
+            return Err(NetError::NotFound);
+        }
+        Ok(records)
+    }
+
+    async fn resolve_system(resolver: &TokioAsyncResolver, hostname: &str, port: u16, record_type: RecordType) -> Result<Vec<DnsRecord>, NetError> {
+        let records = match record_type {
+            RecordType::Address => {
+                resolver.lookup_ip(hostname).await.map_err(Self::net_error)?
+                    .iter()
+                    .map(|ip| DnsRecord::Addr(SocketAddr::new(ip, port)))
+                    .collect()
+            },
+            RecordType::A => {
+                resolver.ipv4_lookup(hostname).await.map_err(Self::net_error)?
+                    .iter()
+                    .map(|ip| DnsRecord::Addr(SocketAddr::new(IpAddr::V4(*ip), port)))
+                    .collect()
+            },
+            RecordType::Aaaa => {
+                resolver.ipv6_lookup(hostname).await.map_err(Self::net_error)?
+                    .iter()
+                    .map(|ip| DnsRecord::Addr(SocketAddr::new(IpAddr::V6(*ip), port)))
+                    .collect()
+            },
+            RecordType::Srv => {
+                resolver.srv_lookup(hostname).await.map_err(Self::net_error)?
+                    .iter()
+                    .map(|srv| DnsRecord::Srv {
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                        port: srv.port(),
+                        target: srv.target().to_utf8(),
+                    })
+                    .collect()
+            },
+            RecordType::Txt => {
+                resolver.txt_lookup(hostname).await.map_err(Self::net_error)?
+                    .iter()
+                    .map(|txt| DnsRecord::Txt(txt.to_string()))
+                    .collect()
+            },
+        };
+
+        Ok(records)
+    }
+
+    fn resolve_static(table: &HashMap<String, HostsEntry>, hostname: &str, port: u16, record_type: RecordType) -> Result<Vec<DnsRecord>, NetError> {
+        let Some(entry) = table.get(&Self::normalize(hostname)) else {
+            return Err(NetError::NotFound);
+        };
+
+        let addrs = entry.addrs.iter().map(|ip| DnsRecord::Addr(SocketAddr::new(*ip, port)));
+        let records = match record_type {
+            RecordType::Address => addrs.collect(),
+            RecordType::A => addrs.filter(|record| matches!(record, DnsRecord::Addr(SocketAddr::V4(_)))).collect(),
+            RecordType::Aaaa => addrs.filter(|record| matches!(record, DnsRecord::Addr(SocketAddr::V6(_)))).collect(),
+            RecordType::Srv => {
+                entry.srv.iter()
+                    .map(|srv| DnsRecord::Srv {
+                        priority: srv.priority,
+                        weight: srv.weight,
+                        port: srv.port,
+                        target: srv.target.clone(),
+                    })
+                    .collect()
+            },
+            RecordType::Txt => entry.txt.iter().cloned().map(DnsRecord::Txt).collect(),
+        };
+
+        Ok(records)
+    }
+
+    fn normalize(name: &str) -> String {
+        name.trim_end_matches('.').to_ascii_lowercase()
+    }
+
+    fn net_error(err: ResolveError) -> NetError {
+        match err.kind() {
+            // NXDOMAIN and empty answers are both reported as no results.
+            ResolveErrorKind::NoRecordsFound { .. } => NetError::NotFound,
+            ResolveErrorKind::Timeout => NetError::TimedOut,
+            _ => NetError::Failed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use super::*;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    fn static_resolver(test: &str) -> Resolver {
+        let path = std::env::temp_dir().join(format!("asi-hosts-{}-{}.json", test, std::process::id()));
+        std::fs::write(&path, r#"{
+            "Service.Example.": {
+                "addrs": ["10.0.0.1", "fd00::1"],
+                "srv": [{ "priority": 1, "weight": 5, "port": 8080, "target": "backend.example" }],
+                "txt": ["v=1"]
+            },
+            "v6only.example": { "addrs": ["fd00::2"] }
+        }"#).unwrap();
+        let resolver = Resolver::from_hosts_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        resolver
+    }
+
+    fn addr(addr: &str) -> DnsRecord {
+        DnsRecord::Addr(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn static_lookup() {
+        let resolver = static_resolver("static_lookup");
+
+        let records = block_on(resolver.resolve("service.example", Some(443), AddressFamily::Any, RecordType::Address)).unwrap();
+        assert_eq!(records, vec![addr("10.0.0.1:443"), addr("[fd00::1]:443")]);
+
+        // Names are matched case-insensitively and with or without the root dot.
+        let records = block_on(resolver.resolve("SERVICE.example.", None, AddressFamily::PreferIpv6, RecordType::Address)).unwrap();
+        assert_eq!(records, vec![addr("[fd00::1]:0"), addr("10.0.0.1:0")]);
+
+        let records = block_on(resolver.resolve("service.example", None, AddressFamily::Any, RecordType::A)).unwrap();
+        assert_eq!(records, vec![addr("10.0.0.1:0")]);
+
+        let records = block_on(resolver.resolve("service.example", None, AddressFamily::Any, RecordType::Srv)).unwrap();
+        assert_eq!(records, vec![DnsRecord::Srv { priority: 1, weight: 5, port: 8080, target: "backend.example".to_string() }]);
+
+        let records = block_on(resolver.resolve("service.example", None, AddressFamily::Any, RecordType::Txt)).unwrap();
+        assert_eq!(records, vec![DnsRecord::Txt("v=1".to_string())]);
+    }
+
+    #[test]
+    fn static_not_found() {
+        let resolver = static_resolver("static_not_found");
+
+        let result = block_on(resolver.resolve("missing.example", None, AddressFamily::Any, RecordType::Address));
+        assert!(matches!(result, Err(NetError::NotFound)));
+
+        // Names without records of the requested type are not found either.
+        let result = block_on(resolver.resolve("v6only.example", None, AddressFamily::Any, RecordType::A));
+        assert!(matches!(result, Err(NetError::NotFound)));
+        let result = block_on(resolver.resolve("v6only.example", None, AddressFamily::Any, RecordType::Txt));
+        assert!(matches!(result, Err(NetError::NotFound)));
+    }
+
+    #[test]
+    fn literal_lookup() {
+        let resolver = static_resolver("literal_lookup");
+
+        let records = block_on(resolver.resolve("192.0.2.1", Some(80), AddressFamily::Any, RecordType::Address)).unwrap();
+        assert_eq!(records, vec![addr("192.0.2.1:80")]);
+        let records = block_on(resolver.resolve("[fd00::3]", None, AddressFamily::Any, RecordType::Aaaa)).unwrap();
+        assert_eq!(records, vec![addr("[fd00::3]:0")]);
+        let result = block_on(resolver.resolve("fd00::3", None, AddressFamily::Any, RecordType::A));
+        assert!(matches!(result, Err(NetError::NotFound)));
+    }
+}
@@ -0,0 +1,192 @@
+use std::{future::poll_fn, io, sync::Mutex, task::Poll};
+
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+use wasi_common::{WasiFile, file::{FdFlags, FileType, SdFlags}, Error, snapshots::preview_1::types::Errno};
+
+/// TCP listener exposed to a guest as a file descriptor.
+///
+/// Accepts wait for a connection on the runtime, suspending the guest without
+/// blocking its thread, unless the guest made the file nonblocking.
+pub struct TcpListenerFile {
+    listener: TcpListener,
+    nonblocking: bool,
+    /// Connection accepted while waiting for the listener to become readable,
+    /// handed out by the next accept.
+    pending: Mutex<Option<TcpStream>>,
+}
+
+/// TCP stream exposed to a guest as a file descriptor.
+///
+/// Reads and writes wait for the socket on the runtime like
+/// [`TcpListenerFile`] accepts.
+pub struct TcpStreamFile {
+    stream: TcpStream,
+    nonblocking: bool,
+    /// Set once the guest shut down reading, reads then see the end of the stream.
+    read_shutdown: bool,
+}
+
+impl TcpListenerFile {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            nonblocking: false,
+            pending: Mutex::new(None),
+        }
+    }
+}
+
+impl TcpStreamFile {
+    pub fn new(stream: TcpStream, nonblocking: bool) -> Self {
+        Self {
+            stream,
+            nonblocking,
+            read_shutdown: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for TcpListenerFile {
+    fn as_any(&self) ->  &dyn std::any::Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::SocketStream)
+    }
+
+    async fn sock_accept(&mut self, fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        let nonblocking = nonblocking_flags(fdflags)?;
+        let pending = self.pending.lock().expect("pending connection lock poisoned").take();
+        let stream = match pending {
+            Some(stream) => stream,
+            None => {
+                let accepted = poll_fn(|cx| match self.listener.poll_accept(cx) {
+                    Poll::Pending if self.nonblocking => Poll::Ready(Err(io::ErrorKind::WouldBlock.into())),
+                    poll => poll,
+                }).await;
+                accepted.map_err(socket_error)?.0
+            },
+        };
+
+        Ok(Box::new(TcpStreamFile::new(stream, nonblocking)))
+    }
+
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(fdflags(self.nonblocking))
+    }
+
+    async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+        self.nonblocking = nonblocking_flags(fdflags)?;
+        Ok(())
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        // Listeners become readable with a pending connection, which is only
+        // known by accepting it.
+        poll_fn(|cx| {
+            let mut pending = self.pending.lock().expect("pending connection lock poisoned");
+            if pending.is_some() {
+                return Poll::Ready(Ok(()));
+            }
+            self.listener.poll_accept(cx).map_ok(|(stream, _)| *pending = Some(stream))
+        }).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for TcpStreamFile {
+    fn as_any(&self) ->  &dyn std::any::Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::SocketStream)
+    }
+
+    async fn write_vectored<'a> (&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        loop {
+            if !self.nonblocking {
+                self.stream.writable().await?;
+            }
+
+            match self.stream.try_write_vectored(bufs) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && !self.nonblocking => continue,
+                result => return Ok(result.map_err(socket_error)? as u64),
+            }
+        }
+    }
+
+    async fn read_vectored<'a> (&mut self, bufs: &mut [std::io::IoSliceMut<'a>]) -> Result<u64, Error> {
+        if self.read_shutdown {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.nonblocking {
+                self.stream.readable().await?;
+            }
+
+            match self.stream.try_read_vectored(bufs) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && !self.nonblocking => continue,
+                result => return Ok(result.map_err(socket_error)? as u64),
+            }
+        }
+    }
+
+    async fn sock_shutdown(&mut self, how: SdFlags) -> Result<(), Error> {
+        if how.contains(SdFlags::RD) {
+            self.read_shutdown = true;
+        }
+        if how.contains(SdFlags::WR) {
+            self.stream.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(fdflags(self.nonblocking))
+    }
+
+    async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+        self.nonblocking = nonblocking_flags(fdflags)?;
+        Ok(())
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.stream.readable().await?;
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.stream.writable().await?;
+        Ok(())
+    }
+}
+
+fn fdflags(nonblocking: bool) -> FdFlags {
+    if nonblocking {
+        FdFlags::NONBLOCK
+    } else {
+        FdFlags::empty()
+    }
+}
+
+/// Whether `fdflags` make a socket nonblocking, sockets take no other flags.
+fn nonblocking_flags(fdflags: FdFlags) -> Result<bool, Error> {
+    if !(fdflags - FdFlags::NONBLOCK).is_empty() {
+        return Err(Errno::Inval.into());
+    }
+    Ok(fdflags.contains(FdFlags::NONBLOCK))
+}
+
+/// Errors of socket calls, an operation that would block a nonblocking guest
+/// file reports `EAGAIN`.
+fn socket_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock => Errno::Again.into(),
+        _ => err.into(),
+    }
+}
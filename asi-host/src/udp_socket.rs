@@ -0,0 +1,86 @@
+use libasi_interop::net::{DATAGRAM_HEADER_LEN, encode_datagram_header, decode_datagram_header};
+use tokio::net::UdpSocket;
+use wasi_common::{WasiFile, file::FileType, Error, snapshots::preview_1::types::Errno};
+
+/// UDP socket exposed to a guest as a file descriptor.
+///
+/// WASI has no datagram addressing, so every read and write carries a
+/// datagram header (see [`libasi_interop::net::DATAGRAM_HEADER_LEN`]) followed
+/// by the payload. One write sends one datagram and one read receives one.
+///
+/// Reads and writes wait for the socket on the runtime, suspending the guest
+/// without blocking its thread.
+pub struct UdpSocketFile {
+    socket: UdpSocket,
+}
+
+impl UdpSocketFile {
+    const MAX_DATAGRAM: usize = 65536;
+
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for UdpSocketFile {
+    fn as_any(&self) ->  &dyn std::any::Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::SocketDgram)
+    }
+
+    async fn write_vectored<'a> (&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let datagram: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        if datagram.len() < DATAGRAM_HEADER_LEN {
+            return Err(Errno::Inval.into());
+        }
+
+        let header = datagram[..DATAGRAM_HEADER_LEN].try_into().expect("slice length of header");
+        let Some(addr) = decode_datagram_header(header) else {
+            return Err(Errno::Inval.into());
+        };
+        let payload = &datagram[DATAGRAM_HEADER_LEN..];
+
+        let sent = if addr.ip().is_unspecified() && addr.port() == 0 {
+            self.socket.send(payload).await?
+        } else {
+            self.socket.send_to(payload, addr).await?
+        };
+
+        Ok((DATAGRAM_HEADER_LEN + sent) as u64)
+    }
+
+    async fn read_vectored<'a> (&mut self, bufs: &mut [std::io::IoSliceMut<'a>]) -> Result<u64, Error> {
+        let mut datagram = vec![0u8; DATAGRAM_HEADER_LEN + Self::MAX_DATAGRAM];
+        let (len, addr) = self.socket.recv_from(&mut datagram[DATAGRAM_HEADER_LEN..]).await?;
+        datagram[..DATAGRAM_HEADER_LEN].copy_from_slice(&encode_datagram_header(&addr));
+        datagram.truncate(DATAGRAM_HEADER_LEN + len);
+
+        // Scatter the datagram across the guest's buffers, anything that does not fit is dropped.
+        let mut remaining = &datagram[..];
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let sz = remaining.len().min(buf.len());
+            buf[..sz].copy_from_slice(&remaining[..sz]);
+            remaining = &remaining[sz..];
+            read += sz;
+        }
+
+        Ok(read as u64)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.socket.readable().await?;
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.socket.writable().await?;
+        Ok(())
+    }
+}
@@ -25,14 +25,70 @@ pub enum ClientRequest {
     Version,
     Shutdown,
     Run {
+        /// Module binary, empty when running a registered `module`.
         binary: Vec<u8>,
+        /// Registered module to run, `<name>` or `<name>@<version>`.
+        module: Option<String>,
+        /// JSON network policy for the process, allow all if not provided.
+        net_policy: Option<Vec<u8>>,
+        /// Output route of the process, the log buffer if not provided.
+        output: Option<String>,
+        /// Process name, the module's own name if not provided.
+        name: Option<String>,
+        /// JSON resource limits the process asks for, the host's defaults if not provided.
+        limits: Option<Vec<u8>>,
+        /// Keep the request open until the process terminates, and respond
+        /// with its final state.
+        wait: bool,
     },
+    /// List the process table.
+    List,
+    /// Kill a running process.
+    Kill {
+        pid: u32,
+    },
+    /// Store a module in the registry as the next version of `name`.
+    Upload {
+        name: String,
+        binary: Vec<u8>,
+    },
+    /// List the registered modules.
+    ListModules,
+    /// Remove a registered module, `<name>` for every version or `<name>@<version>`.
+    RemoveModule {
+        module: String,
+    },
+    /// Statistics of the compiled module cache.
+    CacheStats,
+    /// Drop every compiled module from the cache.
+    ClearCache,
+    SetLogFilter {
+        pid: u32,
+        /// Log filter directives, see [`libasi_interop::diagnostics::LogFilter`].
+        filter: String,
+    },
+    Attach {
+        pid: u32,
+    },
+    Logs {
+        pid: u32,
+        /// Keep streaming new entries until the process exits.
+        follow: bool,
+        /// Log filter directives for records, all records if not provided.
+        filter: Option<String>,
+    },
+}
+
+/// Response body, sent in one piece or streamed in chunks.
+enum Response {
+    Buffer(Vec<u8>),
+    Stream(mpsc::Receiver<Vec<u8>>),
 }
 
 /// In-flight request from the control server.
 pub struct InFlightRequest {
     request: ClientRequest,
-    responder: Option<oneshot::Sender<Result<Vec<u8>, String>>>
+    responder: Option<oneshot::Sender<Result<Response, String>>>
 }
 
 impl InFlightRequest {
@@ -43,6 +99,16 @@ impl InFlightRequest {
 
     /// Send a response to the requester.
     pub fn respond(self, response: Result<Vec<u8>, String>) {
+        self.send_response(response.map(Response::Buffer))
+    }
+
+    /// Stream a successful response to the requester, chunk by chunk until
+    /// every sender of `stream` is dropped.
+    pub fn respond_stream(self, stream: mpsc::Receiver<Vec<u8>>) {
+        self.send_response(Ok(Response::Stream(stream)))
+    }
+
+    fn send_response(self, response: Result<Response, String>) {
         if let Some(responder) = self.responder {
             if let Err(_) = responder.send(response) {
                 // This may fail if the requester has hung up.
@@ -62,7 +128,10 @@ pub struct UdsControlServer {
 
 impl UdsControlServer {
     const POLL_RATE: Duration = Duration::from_millis(50);
-    const MAX_PAYLOAD: u64 = 1024*2024*50;
+    /// Total size of a request's payloads, those of a run include the module binary.
+    const MAX_PAYLOAD: u64 = 1024*1024*50;
+    /// Total size of an upload's payloads, registered modules are kept on disk.
+    const MAX_UPLOAD_PAYLOAD: u64 = 1024*1024*100;
 
     /// Start the control server on a Unix socket at `path`.
     /// 
@@ -164,6 +233,11 @@ impl UdsControlServer {
 
         let op = stream.read_u8()?;
 
+        let max_payload = match op {
+            10 => Self::MAX_UPLOAD_PAYLOAD,
+            _ => Self::MAX_PAYLOAD,
+        };
+
         let mut payloads = vec![];
         let mut total_payload = 0;
         let payload_count = stream.read_u8()?;
@@ -171,7 +245,7 @@ impl UdsControlServer {
             let payload_len = stream.read_u64::<LittleEndian>()?;
 
             total_payload += payload_len;
-            if total_payload > Self::MAX_PAYLOAD {
+            if total_payload > max_payload {
                 return Err(io::Error::new(io::ErrorKind::Other, "payloads too big"));
             }
 
@@ -198,17 +272,157 @@ impl UdsControlServer {
                 request
             },
             2 => {
-                if payload_count != 1 {
-                    return Err(io::Error::new(io::ErrorKind::Other, "run requires one payload"));
+                if !(1..=7).contains(&payload_count) {
+                    return Err(io::Error::new(io::ErrorKind::Other, "run requires one to seven payloads"));
                 }
+                // Optional payloads are skipped by leaving them empty.
+                let mut payloads = payloads.into_iter();
+                let binary = payloads.next().expect("has payload");
+                let net_policy = payloads.next().filter(|payload| !payload.is_empty());
+                let output = match payloads.next().filter(|payload| !payload.is_empty()).map(String::from_utf8).transpose() {
+                    Ok(output) => output,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "output route not UTF-8")),
+                };
+                let name = match payloads.next().filter(|payload| !payload.is_empty()).map(String::from_utf8).transpose() {
+                    Ok(name) => name,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "process name not UTF-8")),
+                };
+                let options = payloads.next().unwrap_or_default();
+                let limits = payloads.next().filter(|payload| !payload.is_empty());
+                let module = match payloads.next().filter(|payload| !payload.is_empty()).map(String::from_utf8).transpose() {
+                    Ok(module) => module,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "module reference not UTF-8")),
+                };
                 let request = InFlightRequest {
                     request: ClientRequest::Run {
-                        binary: payloads.pop().expect("has payload"),
+                        binary,
+                        module,
+                        net_policy,
+                        output,
+                        name,
+                        limits,
+                        wait: matches!(options.first(), Some(options) if options & 1 != 0),
                     },
                     responder: Some(response_send),
                 };
                 request
             },
+            3 => {
+                if payload_count != 2 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "set log filter requires two payloads"));
+                }
+                let Ok(filter) = String::from_utf8(payloads.pop().expect("has payload")) else {
+                    return Err(io::Error::new(io::ErrorKind::Other, "log filter not UTF-8"));
+                };
+                let request = InFlightRequest {
+                    request: ClientRequest::SetLogFilter {
+                        pid: Self::decode_pid(&payloads[0])?,
+                        filter,
+                    },
+                    responder: Some(response_send),
+                };
+                request
+            },
+            4 => {
+                if payload_count != 2 && payload_count != 3 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "logs requires two or three payloads"));
+                }
+                let mut payloads = payloads.into_iter();
+                let pid = Self::decode_pid(&payloads.next().expect("has payload"))?;
+                let options = payloads.next().expect("has payload");
+                let filter = match payloads.next().map(String::from_utf8).transpose() {
+                    Ok(filter) => filter,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "log filter not UTF-8")),
+                };
+                let request = InFlightRequest {
+                    request: ClientRequest::Logs {
+                        pid,
+                        follow: matches!(options.first(), Some(options) if options & 1 != 0),
+                        filter,
+                    },
+                    responder: Some(response_send),
+                };
+                request
+            },
+            5 => {
+                if payload_count != 1 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "attach requires one payload"));
+                }
+                let request = InFlightRequest {
+                    request: ClientRequest::Attach {
+                        pid: Self::decode_pid(&payloads[0])?,
+                    },
+                    responder: Some(response_send),
+                };
+                request
+            },
+            6 => {
+                let request = InFlightRequest {
+                    request: ClientRequest::List,
+                    responder: Some(response_send),
+                };
+                request
+            },
+            7 => {
+                if payload_count != 1 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "kill requires one payload"));
+                }
+                let request = InFlightRequest {
+                    request: ClientRequest::Kill {
+                        pid: Self::decode_pid(&payloads[0])?,
+                    },
+                    responder: Some(response_send),
+                };
+                request
+            },
+            8 => {
+                let request = InFlightRequest {
+                    request: ClientRequest::CacheStats,
+                    responder: Some(response_send),
+                };
+                request
+            },
+            9 => {
+                let request = InFlightRequest {
+                    request: ClientRequest::ClearCache,
+                    responder: Some(response_send),
+                };
+                request
+            },
+            10 => {
+                if payload_count != 2 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "upload requires two payloads"));
+                }
+                let binary = payloads.pop().expect("has payload");
+                let Ok(name) = String::from_utf8(payloads.pop().expect("has payload")) else {
+                    return Err(io::Error::new(io::ErrorKind::Other, "module name not UTF-8"));
+                };
+                let request = InFlightRequest {
+                    request: ClientRequest::Upload { name, binary },
+                    responder: Some(response_send),
+                };
+                request
+            },
+            11 => {
+                let request = InFlightRequest {
+                    request: ClientRequest::ListModules,
+                    responder: Some(response_send),
+                };
+                request
+            },
+            12 => {
+                if payload_count != 1 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "remove module requires one payload"));
+                }
+                let Ok(module) = String::from_utf8(payloads.pop().expect("has payload")) else {
+                    return Err(io::Error::new(io::ErrorKind::Other, "module reference not UTF-8"));
+                };
+                let request = InFlightRequest {
+                    request: ClientRequest::RemoveModule { module },
+                    responder: Some(response_send),
+                };
+                request
+            },
             _ => {
                 return Err(io::Error::new(io::ErrorKind::Other, "bad op"));
             }
@@ -223,10 +437,19 @@ impl UdsControlServer {
         };
 
         match response {
-            Ok(buffer) => {
+            Ok(Response::Buffer(buffer)) => {
                 stream.write_u8(0)?;
                 stream.write_all(&buffer)?;
             },
+            Ok(Response::Stream(chunks)) => {
+                stream.write_u8(0)?;
+                for chunk in chunks {
+                    if stream.write_all(&chunk).is_err() {
+                        // Requester hung up, dropping the receiver ends the stream.
+                        break;
+                    }
+                }
+            },
             Err(message) => {
                 stream.write_u8(1)?;
                 stream.write_all(message.as_bytes())?;
@@ -235,4 +458,11 @@ impl UdsControlServer {
 
         Ok(())
     }
+
+    fn decode_pid(payload: &[u8]) -> Result<u32, Error> {
+        match payload.try_into() {
+            Ok(pid) => Ok(u32::from_le_bytes(pid)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "bad process id")),
+        }
+    }
 }
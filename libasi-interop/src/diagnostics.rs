@@ -1,4 +1,7 @@
+use std::{fmt, str::FromStr};
+
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
 
 use crate::RpcRequest;
 
@@ -25,14 +28,221 @@ impl RpcRequest for PokeRpcRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LogRpcRequest {
     pub target: String,
-    pub level: u32,
+    pub level: LogLevel,
     pub body: String,
     pub module_path: Option<String>,
     pub file: Option<String>,
     pub line: Option<u32>,
+
+    /// Structured key-values of the record.
+    #[serde(default)]
+    pub fields: Vec<LogField>,
+
+    /// Spans the record was logged in, outermost first.
+    #[serde(default)]
+    pub spans: Vec<LogSpan>,
+}
+
+/// Several log records sent at once, in the order they were logged.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogBatchRpcRequest {
+    pub records: Vec<LogRpcRequest>,
+}
+
+impl RpcRequest for LogBatchRpcRequest {
+    type Response = ();
+    const OP_CODE: u32 = DIAGNOSTICS_BASE + 202;
+}
+
+/// A structured key-value attached to a log record or span.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogField {
+    pub key: String,
+    pub value: LogValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LogValue {
+    Str(String),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+/// Named context a record was logged in, with its own key-values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogSpan {
+    pub name: String,
+    pub fields: Vec<LogField>,
 }
 
 impl RpcRequest for LogRpcRequest {
     type Response = ();
     const OP_CODE: u32 = DIAGNOSTICS_BASE + 200;
-}
\ No newline at end of file
+}
+
+/// Fetch the log filter the host applies to the guest.
+///
+/// The host announces later changes with a [`HostEvent::ConfigChanged`](crate::events::HostEvent::ConfigChanged)
+/// for [`LOG_FILTER_CONFIG_KEY`], whose value is the new filter in its
+/// directive form.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogFilterRpcRequest;
+
+impl RpcRequest for LogFilterRpcRequest {
+    type Response = LogFilter;
+    const OP_CODE: u32 = DIAGNOSTICS_BASE + 201;
+}
+
+/// Config key of the guest's log filter.
+pub const LOG_FILTER_CONFIG_KEY: &str = "log.filter";
+
+/// Per-target log level filter.
+///
+/// A target's level is the most verbose level it logs at, `None` disables its
+/// logging. In directive form a filter is a comma separated list of a default
+/// level and `target=level` overrides, e.g. `info,net=debug,net::dns=off`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    pub default: Option<LogLevel>,
+
+    /// Target prefixes with their own level.
+    pub targets: Vec<(String, Option<LogLevel>)>,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid log filter directive '{0}'")]
+pub struct LogFilterParseError(String);
+
+impl LogFilter {
+    /// Filter applying `default` to every target.
+    pub const fn new(default: Option<LogLevel>) -> Self {
+        Self {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Level of `target`, from the longest matching target prefix.
+    pub fn level(&self, target: &str) -> Option<LogLevel> {
+        self.targets.iter()
+            .filter(|(prefix, _)| {
+                matches!(target.strip_prefix(prefix.as_str()), Some(rest) if rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Whether a record of `level` for `target` passes the filter.
+    pub fn enabled(&self, target: &str, level: LogLevel) -> bool {
+        matches!(self.level(target), Some(max) if level <= max)
+    }
+
+    /// Most verbose level enabled for any target.
+    pub fn max_level(&self) -> Option<LogLevel> {
+        self.targets.iter().map(|(_, level)| *level).fold(self.default, Option::max)
+    }
+
+    fn parse_level(level: &str) -> Option<Option<LogLevel>> {
+        match level.trim() {
+            off if off.eq_ignore_ascii_case("off") => Some(None),
+            level => level.parse().ok().map(Some),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = LogFilterParseError;
+
+    fn from_str(directives: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::new(Some(LogLevel::Info));
+        for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let error = || LogFilterParseError(directive.to_string());
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = Self::parse_level(level).ok_or_else(error)?;
+                    filter.targets.push((target.trim().to_string(), level));
+                },
+                None => filter.default = Self::parse_level(directive).ok_or_else(error)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |level: Option<LogLevel>| level.map_or("off", LogLevel::as_str);
+
+        write!(f, "{}", name(self.default))?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, name(*level))?;
+        }
+        Ok(())
+    }
+}
+
+/// Severity of a log record, from most to least severe.
+///
+/// Encoded as its integer value, so values outside the enum are rejected when
+/// a request is decoded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "u32", into = "u32")]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid log level {0}")]
+pub struct InvalidLogLevel(String);
+
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl TryFrom<u32> for LogLevel {
+    type Error = InvalidLogLevel;
+
+    fn try_from(level: u32) -> Result<Self, InvalidLogLevel> {
+        Self::ALL.into_iter()
+            .find(|known| *known as u32 == level)
+            .ok_or_else(|| InvalidLogLevel(level.to_string()))
+    }
+}
+
+impl From<LogLevel> for u32 {
+    fn from(level: LogLevel) -> Self {
+        level as u32
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = InvalidLogLevel;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|known| known.as_str().eq_ignore_ascii_case(level))
+            .ok_or_else(|| InvalidLogLevel(level.to_string()))
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// Length of the request header: a little-endian word holding the opcode in its
+/// low 24 bits and flags in its high 8 bits.
+///
+/// Flags are all clear for a JSON, unpipelined request, whose header is the bare
+/// opcode every host version understands.
+pub const RPC_HEADER_LEN: usize = 4;
+
+/// Bits of the request header holding the opcode, opcodes above it cannot be sent.
+pub const RPC_OP_CODE_MASK: u32 = 0x00ff_ffff;
+
+/// Request header flag marking a pipelined request.
+///
+/// A pipelined request header is followed by a little-endian request id. The
+/// host queues its response as a frame (see [`RPC_FRAME_HEADER_LEN`]) instead
+/// of replacing the pending response, so several responses can be left unread
+/// and collected in any order. The host still services requests one at a time,
+/// in the order they are written.
+///
+/// The host holds a bounded number of unread responses, a pipelined request
+/// written while it is full fails with `EAGAIN` until responses are read.
+pub const RPC_FLAG_PIPELINED: u32 = 1 << 31;
+
+/// Length of the request id that follows a pipelined request header.
+pub const RPC_REQUEST_ID_LEN: usize = 4;
+
+/// Length of a response frame header: the little-endian request id followed by
+/// the little-endian length of the encoded response.
+pub const RPC_FRAME_HEADER_LEN: usize = 8;
+
+/// Wire encoding of an RPC request and its response, selected by bits 24 to 30
+/// of the request header. The host replies in the encoding of the
+/// request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcEncoding {
+    /// Human-readable JSON, useful for debugging.
+    Json,
+
+    /// Compact binary encoding.
+    Postcard,
+}
+
+#[derive(Error, Debug)]
+#[error("RPC encoding failed")]
+pub struct EncodingError;
+
+impl RpcEncoding {
+    const FLAGS_SHIFT: u32 = 24;
+    const FLAGS_MASK: u32 = 0x7f << Self::FLAGS_SHIFT;
+
+    pub fn from_flags(flags: u32) -> Option<Self> {
+        match (flags & Self::FLAGS_MASK) >> Self::FLAGS_SHIFT {
+            0 => Some(RpcEncoding::Json),
+            1 => Some(RpcEncoding::Postcard),
+            _ => None,
+        }
+    }
+
+    pub fn to_flags(self) -> u32 {
+        let encoding = match self {
+            RpcEncoding::Json => 0,
+            RpcEncoding::Postcard => 1,
+        };
+        encoding << Self::FLAGS_SHIFT
+    }
+
+    /// Append the encoding of `value` to `buffer`.
+    pub fn encode_into<T: Serialize>(self, value: &T, buffer: &mut Vec<u8>) -> Result<(), EncodingError> {
+        match self {
+            RpcEncoding::Json => serde_json::to_writer(buffer, value).map_err(|_| EncodingError),
+            RpcEncoding::Postcard => {
+                *buffer = postcard::to_extend(value, std::mem::take(buffer)).map_err(|_| EncodingError)?;
+                Ok(())
+            },
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, EncodingError> {
+        let mut buffer = Vec::new();
+        self.encode_into(value, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, buffer: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            RpcEncoding::Json => serde_json::from_slice(buffer).map_err(|_| EncodingError),
+            RpcEncoding::Postcard => postcard::from_bytes(buffer).map_err(|_| EncodingError),
+        }
+    }
+}
+
+/// Encode a request header for `op_code`, which must fit [`RPC_OP_CODE_MASK`].
+pub fn encode_header(op_code: u32, flags: u32) -> [u8; RPC_HEADER_LEN] {
+    debug_assert_eq!(op_code & !RPC_OP_CODE_MASK, 0, "opcode overlaps header flags");
+    ((op_code & RPC_OP_CODE_MASK) | (flags & !RPC_OP_CODE_MASK)).to_le_bytes()
+}
+
+/// Decode a request header into its opcode and flags.
+pub fn decode_header(header: &[u8; RPC_HEADER_LEN]) -> (u32, u32) {
+    let word = u32::from_le_bytes(*header);
+    (word & RPC_OP_CODE_MASK, word & !RPC_OP_CODE_MASK)
+}
+
+/// Encode a response frame header.
+pub fn encode_frame_header(request_id: u32, len: u32) -> [u8; RPC_FRAME_HEADER_LEN] {
+    let mut header = [0u8; RPC_FRAME_HEADER_LEN];
+    header[..4].copy_from_slice(&request_id.to_le_bytes());
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+/// Decode a response frame header into its request id and response length.
+pub fn decode_frame_header(header: &[u8; RPC_FRAME_HEADER_LEN]) -> (u32, u32) {
+    let request_id = u32::from_le_bytes(header[..4].try_into().expect("slice length of 4"));
+    let len = u32::from_le_bytes(header[4..].try_into().expect("slice length of 4"));
+    (request_id, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_header_is_bare_opcode() {
+        // JSON unpipelined requests keep the layout of hosts without encodings.
+        let header = encode_header(4001, RpcEncoding::Json.to_flags());
+        assert_eq!(header, 4001u32.to_le_bytes());
+        assert_eq!(decode_header(&4001u32.to_le_bytes()), (4001, 0));
+    }
+
+    #[test]
+    fn header_flags_round_trip() {
+        let flags = RpcEncoding::Postcard.to_flags() | RPC_FLAG_PIPELINED;
+        let (op_code, decoded) = decode_header(&encode_header(10200, flags));
+        assert_eq!(op_code, 10200);
+        assert_eq!(decoded, flags);
+        assert_eq!(RpcEncoding::from_flags(decoded), Some(RpcEncoding::Postcard));
+        assert_ne!(decoded & RPC_FLAG_PIPELINED, 0);
+
+        assert_eq!(RpcEncoding::from_flags(0), Some(RpcEncoding::Json));
+        assert_eq!(RpcEncoding::from_flags(RPC_FLAG_PIPELINED), Some(RpcEncoding::Json));
+        assert_eq!(RpcEncoding::from_flags(2 << 24), None);
+    }
+
+    #[test]
+    fn frame_header_round_trip() {
+        assert_eq!(decode_frame_header(&encode_frame_header(7, 1234)), (7, 1234));
+    }
+}
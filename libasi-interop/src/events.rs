@@ -0,0 +1,84 @@
+use serde::{Serialize, Deserialize};
+
+use crate::RpcRequest;
+
+const EVENTS_BASE: u32 = 5000;
+
+/// Name of the environment variable holding the guest's host event descriptor.
+pub const EVENTS_FD_ENV: &str = "ASI_EVENTS_FD";
+
+/// Length of an event frame header: the little-endian length of the postcard
+/// encoded [`HostEvent`] that follows.
+pub const EVENT_FRAME_HEADER_LEN: usize = 4;
+
+/// Notification pushed by the host on the event descriptor.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HostEvent {
+    /// The host is shutting down, the guest should exit.
+    Shutdown,
+
+    /// A host setting affecting the guest changed.
+    ConfigChanged {
+        key: String,
+        value: String,
+    },
+
+    /// A timer set with [`SetTimerRpcRequest`] expired.
+    Timer {
+        timer_id: u64,
+    },
+}
+
+/// Encode `event` as a frame for the event descriptor.
+pub fn encode_event_frame(event: &HostEvent) -> Result<Vec<u8>, postcard::Error> {
+    let mut frame = vec![0u8; EVENT_FRAME_HEADER_LEN];
+    frame = postcard::to_extend(event, frame)?;
+    let len = (frame.len() - EVENT_FRAME_HEADER_LEN) as u32;
+    frame[..EVENT_FRAME_HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+    Ok(frame)
+}
+
+/// Decode an event frame header into the length of the event.
+pub fn decode_event_frame_header(header: &[u8; EVENT_FRAME_HEADER_LEN]) -> u32 {
+    u32::from_le_bytes(*header)
+}
+
+/// Decode the body of an event frame.
+pub fn decode_event(body: &[u8]) -> Result<HostEvent, postcard::Error> {
+    postcard::from_bytes(body)
+}
+
+/// Shortest interval of a repeating timer.
+pub const MIN_TIMER_INTERVAL_MS: u64 = 10;
+
+/// Most timers a guest can have running at once.
+pub const MAX_TIMERS: usize = 64;
+
+/// Deliver a [`HostEvent::Timer`] after `delay_ms`, then every `interval_ms`
+/// if given. Setting an existing `timer_id` replaces that timer.
+///
+/// Intervals below [`MIN_TIMER_INTERVAL_MS`] and new timers beyond
+/// [`MAX_TIMERS`] are rejected. A timer that expires again before the guest
+/// read its previous event is delivered once.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetTimerRpcRequest {
+    pub timer_id: u64,
+    pub delay_ms: u64,
+    pub interval_ms: Option<u64>,
+}
+
+impl RpcRequest for SetTimerRpcRequest {
+    type Response = ();
+    const OP_CODE: u32 = EVENTS_BASE + 1;
+}
+
+/// Stop a timer, responds whether the timer existed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelTimerRpcRequest {
+    pub timer_id: u64,
+}
+
+impl RpcRequest for CancelTimerRpcRequest {
+    type Response = bool;
+    const OP_CODE: u32 = EVENTS_BASE + 2;
+}
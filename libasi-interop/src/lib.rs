@@ -2,6 +2,8 @@ use serde::{Serialize, de::DeserializeOwned, Deserialize};
 use thiserror::Error;
 
 pub mod diagnostics;
+pub mod encoding;
+pub mod events;
 pub mod net;
 
 pub trait RpcRequest: Serialize + DeserializeOwned {
@@ -10,7 +12,36 @@ pub trait RpcRequest: Serialize + DeserializeOwned {
     const OP_CODE: u32;
 }
 
-#[derive(Error, Serialize, Deserialize, Debug)]
+/// Version of the guest/host RPC protocol described by this crate.
+///
+/// Version 2 added pipelined requests, see [`encoding::RPC_FLAG_PIPELINED`].
+pub const RPC_PROTOCOL_VERSION: u32 = 2;
+
+/// First request issued on an RPC device, always JSON encoded so that it is
+/// understood by every host version.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HandshakeRpcRequest {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandshakeResponse {
+    /// Protocol version spoken by the host.
+    pub version: u32,
+
+    /// Opcodes the host can service.
+    pub op_codes: Vec<u32>,
+
+    /// Request encodings the host accepts, as header flag values.
+    pub encodings: Vec<u32>,
+}
+
+impl RpcRequest for HandshakeRpcRequest {
+    type Response = HandshakeResponse;
+    const OP_CODE: u32 = 1;
+}
+
+#[derive(Error, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AsiRpcError {
     /// The underlying a-Si system request file desciptor is invalid or in a bad state.
     #[error("bad descriptor")]
@@ -23,6 +54,10 @@ pub enum AsiRpcError {
     /// The a-Si host send an invalid response.
     #[error("bad response")]
     BadResponse,
+
+    /// The program is not running in an a-Si environment.
+    #[error("a-Si environment unavailable")]
+    Unavailable,
 }
 
 pub type AsiFd = i32;
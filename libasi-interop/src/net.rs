@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
@@ -15,6 +15,18 @@ pub enum NetError {
     #[error("query returned no results")]
     NotFound,
 
+    #[error("connection refused")]
+    ConnectionRefused,
+
+    #[error("operation timed out")]
+    TimedOut,
+
+    #[error("host or network unreachable")]
+    Unreachable,
+
+    #[error("address in use")]
+    AddressInUse,
+
     #[error("operation failed")]
     Failed,
 }
@@ -28,7 +40,10 @@ pub struct BindRpcRequest {
 pub enum BindAddr {
     Tcp {
         addr: SocketAddr,
-    }
+    },
+    Udp {
+        addr: SocketAddr,
+    },
 }
 
 impl RpcRequest for BindRpcRequest {
@@ -50,7 +65,10 @@ impl RpcRequest for ConnectRpcRequest {
 pub enum ConnectAddrs {
     Tcp {
         addrs: Vec<SocketAddr>,
-    }
+    },
+    Udp {
+        addrs: Vec<SocketAddr>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -62,3 +80,95 @@ impl RpcRequest for LookupRpcRequest {
     type Response = Result<Vec<SocketAddr>, NetError>;
     const OP_CODE: u32 = NET_BASE + 3;
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResolveRpcRequest {
+    pub hostname: String,
+
+    /// Port to attach to address records, 0 if not provided.
+    pub port: Option<u16>,
+
+    /// Ordering of `RecordType::Address` results.
+    pub family: AddressFamily,
+
+    pub record_type: RecordType,
+}
+
+impl RpcRequest for ResolveRpcRequest {
+    type Response = Result<Vec<DnsRecord>, NetError>;
+    const OP_CODE: u32 = NET_BASE + 4;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Return addresses in resolver order.
+    Any,
+
+    /// Return IPv4 addresses before IPv6 addresses.
+    PreferIpv4,
+
+    /// Return IPv6 addresses before IPv4 addresses.
+    PreferIpv6,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// Both A and AAAA records.
+    Address,
+    A,
+    Aaaa,
+    Srv,
+    Txt,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DnsRecord {
+    /// An A or AAAA record, with the requested port.
+    Addr(SocketAddr),
+
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+
+    Txt(String),
+}
+
+/// Length of the address header that prefixes every datagram read from or
+/// written to a UDP socket descriptor.
+///
+/// The header is one family byte (4 or 6), 16 bytes of address (IPv4
+/// addresses use the first 4) and a little-endian port. On writes to a
+/// connected socket an unspecified address with port 0 sends to the peer.
+pub const DATAGRAM_HEADER_LEN: usize = 19;
+
+pub fn encode_datagram_header(addr: &SocketAddr) -> [u8; DATAGRAM_HEADER_LEN] {
+    let mut header = [0u8; DATAGRAM_HEADER_LEN];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            header[0] = 4;
+            header[1..5].copy_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            header[0] = 6;
+            header[1..17].copy_from_slice(&ip.octets());
+        },
+    }
+    header[17..].copy_from_slice(&addr.port().to_le_bytes());
+    header
+}
+
+pub fn decode_datagram_header(header: &[u8; DATAGRAM_HEADER_LEN]) -> Option<SocketAddr> {
+    let port = u16::from_le_bytes([header[17], header[18]]);
+    let ip = match header[0] {
+        4 => IpAddr::V4(Ipv4Addr::new(header[1], header[2], header[3], header[4])),
+        6 => {
+            let octets: [u8; 16] = header[1..17].try_into().expect("slice length of 16");
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
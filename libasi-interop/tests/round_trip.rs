@@ -0,0 +1,168 @@
+//! Every RPC request and its response survive encoding and decoding, in both
+//! encodings, as they travel between guest and host.
+
+use std::fmt::Debug;
+
+use libasi_interop::{
+    AsiRpcError, RpcRequest, HandshakeRpcRequest, HandshakeResponse,
+    diagnostics::{HelloRpcRequest, PokeRpcRequest, LogRpcRequest, LogBatchRpcRequest, LogFilterRpcRequest, LogField, LogValue, LogSpan, LogLevel},
+    encoding::{RpcEncoding, RPC_HEADER_LEN, RPC_OP_CODE_MASK, decode_header, encode_header},
+    events::{SetTimerRpcRequest, CancelTimerRpcRequest},
+    net::{BindRpcRequest, BindAddr, ConnectRpcRequest, ConnectAddrs, LookupRpcRequest, ResolveRpcRequest, AddressFamily, RecordType, DnsRecord, NetError},
+};
+
+const ENCODINGS: [RpcEncoding; 2] = [RpcEncoding::Json, RpcEncoding::Postcard];
+
+/// Encode `request` with its header and `response` as the host replies, decode
+/// both back and check nothing changed.
+fn round_trip<T: RpcRequest + Debug>(request: T, response: T::Response)
+where
+    T::Response: Debug,
+{
+    assert_eq!(T::OP_CODE & !RPC_OP_CODE_MASK, 0, "opcode overlaps header flags");
+
+    for encoding in ENCODINGS {
+        let mut buffer = encode_header(T::OP_CODE, encoding.to_flags()).to_vec();
+        encoding.encode_into(&request, &mut buffer).unwrap();
+
+        let (op_code, flags) = decode_header(buffer[..RPC_HEADER_LEN].try_into().unwrap());
+        assert_eq!(op_code, T::OP_CODE);
+        assert_eq!(RpcEncoding::from_flags(flags), Some(encoding));
+        let decoded: T = encoding.decode(&buffer[RPC_HEADER_LEN..]).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", request), "{:?} request", encoding);
+
+        let results: [Result<&T::Response, AsiRpcError>; 2] = [Ok(&response), Err(AsiRpcError::BadRequest)];
+        for result in results {
+            let encoded = encoding.encode(&result).unwrap();
+            let decoded: Result<T::Response, AsiRpcError> = encoding.decode(&encoded).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", result), "{:?} response", encoding);
+        }
+    }
+}
+
+#[test]
+fn handshake() {
+    round_trip(HandshakeRpcRequest { version: 2 }, HandshakeResponse {
+        version: 2,
+        op_codes: vec![1, 4001, 10200],
+        encodings: vec![RpcEncoding::Json.to_flags(), RpcEncoding::Postcard.to_flags()],
+    });
+}
+
+#[test]
+fn hello() {
+    round_trip(HelloRpcRequest { who: "world".to_string() }, ());
+}
+
+#[test]
+fn poke() {
+    round_trip(PokeRpcRequest, u64::MAX);
+}
+
+fn log_record() -> LogRpcRequest {
+    LogRpcRequest {
+        target: "app::net".to_string(),
+        level: LogLevel::Warn,
+        body: "retrying \"connect\"".to_string(),
+        module_path: Some("app::net".to_string()),
+        file: Some("src/net.rs".to_string()),
+        line: Some(42),
+        fields: vec![
+            LogField { key: "attempt".to_string(), value: LogValue::U64(3) },
+            LogField { key: "delta".to_string(), value: LogValue::I64(-7) },
+            LogField { key: "ratio".to_string(), value: LogValue::F64(0.5) },
+            LogField { key: "fatal".to_string(), value: LogValue::Bool(false) },
+        ],
+        spans: vec![LogSpan {
+            name: "request".to_string(),
+            fields: vec![LogField { key: "id".to_string(), value: LogValue::Str("abc".to_string()) }],
+        }],
+    }
+}
+
+#[test]
+fn log() {
+    round_trip(log_record(), ());
+    round_trip(LogRpcRequest {
+        target: String::new(),
+        level: LogLevel::Trace,
+        body: String::new(),
+        module_path: None,
+        file: None,
+        line: None,
+        fields: vec![],
+        spans: vec![],
+    }, ());
+}
+
+#[test]
+fn log_batch() {
+    round_trip(LogBatchRpcRequest { records: vec![log_record(), log_record()] }, ());
+}
+
+#[test]
+fn log_filter() {
+    round_trip(LogFilterRpcRequest, "info,net=debug,net::dns=off".parse().unwrap());
+}
+
+#[test]
+fn set_timer() {
+    round_trip(SetTimerRpcRequest { timer_id: 9, delay_ms: 250, interval_ms: Some(1000) }, ());
+    round_trip(SetTimerRpcRequest { timer_id: 10, delay_ms: 0, interval_ms: None }, ());
+}
+
+#[test]
+fn cancel_timer() {
+    round_trip(CancelTimerRpcRequest { timer_id: 9 }, true);
+}
+
+#[test]
+fn bind() {
+    round_trip(BindRpcRequest { bind_addr: BindAddr::Tcp { addr: "0.0.0.0:8080".parse().unwrap() } }, Ok(5));
+    round_trip(BindRpcRequest { bind_addr: BindAddr::Udp { addr: "[::]:53".parse().unwrap() } }, Err(NetError::AddressInUse));
+}
+
+#[test]
+fn connect() {
+    let addrs = vec!["10.0.0.1:80".parse().unwrap(), "[fd00::1]:80".parse().unwrap()];
+    round_trip(ConnectRpcRequest { target: ConnectAddrs::Tcp { addrs: addrs.clone() } }, Ok(6));
+    round_trip(ConnectRpcRequest { target: ConnectAddrs::Udp { addrs } }, Err(NetError::AccessDenied));
+}
+
+#[test]
+fn lookup() {
+    round_trip(LookupRpcRequest { query: "example.com:443".to_string() }, Ok(vec!["93.184.216.34:443".parse().unwrap()]));
+    round_trip(LookupRpcRequest { query: "missing.example".to_string() }, Err(NetError::NotFound));
+}
+
+#[test]
+fn resolve() {
+    let request = || ResolveRpcRequest {
+        hostname: "_http._tcp.example.com".to_string(),
+        port: Some(80),
+        family: AddressFamily::PreferIpv6,
+        record_type: RecordType::Srv,
+    };
+    round_trip(request(), Ok(vec![
+        DnsRecord::Addr("[fd00::1]:80".parse().unwrap()),
+        DnsRecord::Srv { priority: 1, weight: 10, port: 8080, target: "backend.example.com".to_string() },
+        DnsRecord::Txt("v=spf1 -all".to_string()),
+    ]));
+    round_trip(request(), Err(NetError::TimedOut));
+}
+
+#[test]
+fn unknown_log_level() {
+    let json = RpcEncoding::Json.encode(&log_record()).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains("\"level\":2"));
+    let json = json.replace("\"level\":2", "\"level\":9");
+    assert!(RpcEncoding::Json.decode::<LogRpcRequest>(json.as_bytes()).is_err());
+
+    // The level follows the length prefixed target, levels fit in one varint byte.
+    let mut postcard = RpcEncoding::Postcard.encode(&log_record()).unwrap();
+    let level = 1 + "app::net".len();
+    assert_eq!(postcard[level], 2);
+    postcard[level] = 9;
+    assert!(RpcEncoding::Postcard.decode::<LogRpcRequest>(&postcard).is_err());
+}
@@ -0,0 +1,113 @@
+use std::{fs::File, io::{self, Read}, mem::ManuallyDrop, os::fd::{FromRawFd, AsRawFd, RawFd}, time::Duration};
+
+use libasi_interop::{diagnostics::LOG_FILTER_CONFIG_KEY, events::{SetTimerRpcRequest, CancelTimerRpcRequest, EVENTS_FD_ENV, EVENT_FRAME_HEADER_LEN, decode_event_frame_header, decode_event}};
+
+pub use libasi_interop::events::HostEvent;
+
+use crate::AsiRpcError;
+
+use super::rpc::{try_rpc_call, expect_rpc};
+
+/// Events pushed by the a-Si host, in the order they were sent.
+///
+/// Iteration blocks until the next event arrives and ends if the host closes
+/// the channel. The descriptor can be waited on with `poll_oneoff` alongside
+/// the program's other files, see [`AsRawFd`]. Every [`Events`] reads from
+/// the same channel, so each event is delivered to only one of them.
+pub struct Events {
+    file: ManuallyDrop<File>,
+}
+
+impl Events {
+    /// Read the next event, `None` if the host closed the channel.
+    ///
+    /// Buffered log records are sent first, the program may wait here a while.
+    pub fn next_event(&mut self) -> io::Result<Option<HostEvent>> {
+        crate::log::flush();
+
+        let mut header = [0u8; EVENT_FRAME_HEADER_LEN];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let mut body = vec![0u8; decode_event_frame_header(&header) as usize];
+        self.file.read_exact(&mut body)?;
+
+        match decode_event(&body) {
+            Ok(event) => {
+                // Log filter changes take effect before the program sees them.
+                if let HostEvent::ConfigChanged { key, value } = &event {
+                    if key == LOG_FILTER_CONFIG_KEY {
+                        crate::log::set_filter(value);
+                    }
+                }
+                Ok(Some(event))
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "bad host event")),
+        }
+    }
+}
+
+impl Iterator for Events {
+    type Item = HostEvent;
+
+    fn next(&mut self) -> Option<HostEvent> {
+        self.next_event().ok().flatten()
+    }
+}
+
+impl AsRawFd for Events {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Events pushed by the a-Si host.
+pub fn events() -> Events {
+    match try_events() {
+        Ok(events) => events,
+        Err(err) => panic!("a-Si host events unavailable: {}", err),
+    }
+}
+
+pub fn try_events() -> Result<Events, AsiRpcError> {
+    let fd_str = match std::env::var(EVENTS_FD_ENV) {
+        Ok(str) => str,
+        Err(_) => return Err(AsiRpcError::Unavailable),
+    };
+
+    let fd: RawFd = fd_str.parse().unwrap_or(-1);
+    if fd < 0 {
+        return Err(AsiRpcError::BadDescriptor);
+    }
+
+    // The descriptor is shared by every `Events` and lives as long as the program.
+    Ok(Events {
+        file: ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }),
+    })
+}
+
+/// Receive [`HostEvent::Timer`] with `timer_id` after `delay`, then every
+/// `interval` if given. Replaces any timer with the same id.
+pub fn set_timer(timer_id: u64, delay: Duration, interval: Option<Duration>) {
+    expect_rpc::<SetTimerRpcRequest, _>(try_set_timer(timer_id, delay, interval))
+}
+
+pub fn try_set_timer(timer_id: u64, delay: Duration, interval: Option<Duration>) -> Result<(), AsiRpcError> {
+    try_rpc_call(&SetTimerRpcRequest {
+        timer_id,
+        delay_ms: delay.as_millis() as u64,
+        interval_ms: interval.map(|interval| interval.as_millis() as u64),
+    })
+}
+
+/// Stop the timer `timer_id`, returns whether it existed.
+pub fn cancel_timer(timer_id: u64) -> bool {
+    expect_rpc::<CancelTimerRpcRequest, _>(try_cancel_timer(timer_id))
+}
+
+pub fn try_cancel_timer(timer_id: u64) -> Result<bool, AsiRpcError> {
+    try_rpc_call(&CancelTimerRpcRequest { timer_id })
+}
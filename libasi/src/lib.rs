@@ -1,17 +1,58 @@
-use libasi_interop::diagnostics::{HelloRpcRequest, PokeRpcRequest};
+use libasi_interop::{RpcRequest, diagnostics::{HelloRpcRequest, PokeRpcRequest}};
 
-use self::rpc::rpc_call;
+use self::rpc::{rpc_call, try_rpc_call};
 
+pub use self::rpc::PendingCall;
+
+pub use libasi_interop as interop;
+pub use libasi_interop::AsiRpcError;
+
+pub mod events;
 pub mod log;
 pub mod net;
 mod rpc;
 
+/// Whether the program is running in an a-Si environment, so the same
+/// binary can also run under a plain WASI runtime.
+pub fn is_asi_environment() -> bool {
+    rpc::is_available()
+}
+
+/// Whether the host supports the RPC `T`, so apps can degrade gracefully on
+/// older hosts. Always false if the host predates the version handshake.
+pub fn host_supports<T: RpcRequest>() -> bool {
+    rpc::host_supports(T::OP_CODE)
+}
+
+/// RPC protocol version of the host, if it took part in the version handshake.
+pub fn host_protocol_version() -> Option<u32> {
+    rpc::host_version()
+}
+
+/// Send `request` to the host without collecting the response, so several
+/// requests can be issued before any response is read. The host services
+/// requests one at a time in the order they are sent, they do not run
+/// concurrently. Collect the response with [`PendingCall::wait`].
+pub fn submit<T: RpcRequest>(request: &T) -> Result<PendingCall<T>, AsiRpcError> {
+    rpc::try_rpc_submit(request)
+}
+
 pub fn hello(who: impl ToString) {
     rpc_call(&HelloRpcRequest {
         who: who.to_string(),
     })
 }
 
+pub fn try_hello(who: impl ToString) -> Result<(), AsiRpcError> {
+    try_rpc_call(&HelloRpcRequest {
+        who: who.to_string(),
+    })
+}
+
 pub fn poke() -> u64 {
     rpc_call(&PokeRpcRequest)
 }
+
+pub fn try_poke() -> Result<u64, AsiRpcError> {
+    try_rpc_call(&PokeRpcRequest)
+}
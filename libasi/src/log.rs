@@ -1,35 +1,424 @@
-use libasi_interop::diagnostics::LogRpcRequest;
-use log::{SetLoggerError, LevelFilter, Metadata, Record};
+use std::{cell::RefCell, marker::PhantomData, sync::{Mutex, RwLock}, time::{Duration, Instant}};
 
-use crate::rpc::rpc_call;
+use libasi_interop::{RpcRequest, diagnostics::{LogRpcRequest, LogBatchRpcRequest, LogFilterRpcRequest, LogFilter, LogLevel, LogField, LogSpan, LogValue}};
+use log::{SetLoggerError, LevelFilter, Metadata, Record, kv::{self, Source, VisitSource, Key, Value}};
+
+use crate::rpc::{try_rpc_call, host_supports};
+
+thread_local!(
+    /// Spans entered on this thread, outermost first.
+    static SPANS: RefCell<Vec<LogSpan>> = const { RefCell::new(Vec::new()) }
+);
+
+/// Log filter set by the host, records it rejects are dropped without an RPC.
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new(Some(LogLevel::Info)));
+
+/// When the filter was last fetched from the host.
+static FILTER_FETCHED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// How often the filter is fetched again, for programs that do not read
+/// their host events and so miss change notifications. Refreshes are checked
+/// for when an enabled record is logged, the `log` max level keeps the others
+/// from reaching the logger.
+const FILTER_REFRESH: Duration = Duration::from_secs(5);
+
+/// Records waiting to be sent to the host in a single batch.
+static BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    records: Vec::new(),
+    oldest: None,
+});
+
+/// Number of buffered records that triggers a flush.
+const BATCH_SIZE: usize = 64;
+
+/// Age of the oldest buffered record that triggers a flush.
+///
+/// There is no background thread to flush after the delay, the age is checked
+/// whenever a record is logged. Records also go out when the program waits for
+/// host events, see [`crate::events::Events::next_event`].
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+struct LogBuffer {
+    records: Vec<LogRpcRequest>,
+    /// When the oldest buffered record was logged.
+    oldest: Option<Instant>,
+}
+
+impl LogBuffer {
+    fn push(&mut self, record: LogRpcRequest) {
+        self.oldest.get_or_insert_with(Instant::now);
+        self.records.push(record);
+    }
+
+    fn take(&mut self) -> Vec<LogRpcRequest> {
+        self.oldest = None;
+        std::mem::take(&mut self.records)
+    }
+
+    /// Whether the buffered records waited long enough to be sent.
+    fn due(&self) -> bool {
+        matches!(self.oldest, Some(oldest) if oldest.elapsed() >= BATCH_DELAY)
+    }
+}
 
 struct AsiLogger;
 
 impl log::Log for AsiLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.read().expect("log filter lock poisoned").enabled(metadata.target(), asi_level(metadata.level()))
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
+            refresh_filter(false);
+
             let log_rpc_req = LogRpcRequest {
                 target: record.target().to_string(),
-                level: record.level() as u32,
+                level: asi_level(record.level()),
                 body: format!("{}", record.args()),
                 module_path: record.module_path().map(String::from),
                 file: record.file().map(String::from),
                 line: record.line(),
+                fields: collect_fields(record.key_values()),
+                spans: SPANS.with(|spans| spans.borrow().clone()),
             };
-            rpc_call(&log_rpc_req);
+
+            let batch = {
+                let mut buffer = BUFFER.lock().expect("log buffer lock poisoned");
+                buffer.push(log_rpc_req);
+
+                // Errors go out immediately, along with everything logged before them.
+                if record.level() == log::Level::Error || buffer.records.len() >= BATCH_SIZE || buffer.due() {
+                    buffer.take()
+                } else {
+                    Vec::new()
+                }
+            };
+            send_batch(batch);
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        flush();
+    }
+}
+
+/// Send the buffered records to the host.
+pub(crate) fn flush() {
+    let batch = BUFFER.lock().expect("log buffer lock poisoned").take();
+    send_batch(batch);
+}
+
+/// Send buffered records to the host.
+fn send_batch(records: Vec<LogRpcRequest>) {
+    // Tests have no host, they capture the records instead.
+    #[cfg(test)]
+    let records = tests::capture(records);
+
+    // Failed log calls must not take the program down with them.
+    match records.len() {
+        0 => (),
+        1 => {
+            let _ = try_rpc_call(&records[0]);
+        },
+        _ if host_supports(LogBatchRpcRequest::OP_CODE) => {
+            let _ = try_rpc_call(&LogBatchRpcRequest { records });
+        },
+        _ => {
+            // Hosts that predate batching take the records one at a time.
+            for record in &records {
+                let _ = try_rpc_call(record);
+            }
+        },
+    }
+}
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> i32;
+}
+
+extern "C" fn flush_at_exit() {
+    log::logger().flush();
 }
 
 static LOGGER: AsiLogger = AsiLogger;
 
+/// Install the a-Si logger.
+///
+/// Records are buffered and sent to the host in batches of up to 64, once
+/// the oldest is a second old. Errors and [`log::Log::flush`] send the buffer
+/// immediately, and it is flushed when the program waits for host events and
+/// when it exits. Programs that stop logging and never read events should
+/// flush themselves.
 pub fn init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Info))
+    log::set_logger(&LOGGER)?;
+    // The default filter applies until the host's is fetched.
+    log::set_max_level(level_filter(FILTER.read().expect("log filter lock poisoned").max_level()));
+    refresh_filter(true);
+
+    // Only fails if the handler table is full, records still go out on the next flush.
+    let _ = unsafe { atexit(flush_at_exit) };
+    Ok(())
+}
+
+/// Apply a filter in directive form, as announced by the host.
+pub(crate) fn set_filter(directives: &str) {
+    if let Ok(filter) = directives.parse() {
+        apply_filter(filter);
+    }
+}
+
+/// Fetch the filter from the host if `force`d or it is due for a refresh.
+fn refresh_filter(force: bool) {
+    {
+        let mut fetched = FILTER_FETCHED.lock().expect("log filter lock poisoned");
+        if !force && matches!(*fetched, Some(fetched) if fetched.elapsed() < FILTER_REFRESH) {
+            return;
+        }
+        *fetched = Some(Instant::now());
+    }
+
+    // Hosts that predate filtering leave the default in place.
+    if let Ok(filter) = try_rpc_call(&LogFilterRpcRequest) {
+        apply_filter(filter);
+    }
+}
+
+fn apply_filter(filter: LogFilter) {
+    // Records the filter rejects for every target are dropped by `log` itself.
+    log::set_max_level(level_filter(filter.max_level()));
+    *FILTER.write().expect("log filter lock poisoned") = filter;
+}
+
+fn level_filter(level: Option<LogLevel>) -> LevelFilter {
+    match level {
+        None => LevelFilter::Off,
+        Some(LogLevel::Error) => LevelFilter::Error,
+        Some(LogLevel::Warn) => LevelFilter::Warn,
+        Some(LogLevel::Info) => LevelFilter::Info,
+        Some(LogLevel::Debug) => LevelFilter::Debug,
+        Some(LogLevel::Trace) => LevelFilter::Trace,
+    }
+}
+
+fn asi_level(level: log::Level) -> LogLevel {
+    match level {
+        log::Level::Error => LogLevel::Error,
+        log::Level::Warn => LogLevel::Warn,
+        log::Level::Info => LogLevel::Info,
+        log::Level::Debug => LogLevel::Debug,
+        log::Level::Trace => LogLevel::Trace,
+    }
+}
+
+/// Guard for an entered span, the span is exited when the guard is dropped.
+#[must_use = "the span is exited when the guard is dropped"]
+pub struct SpanGuard {
+    depth: usize,
+    // Spans are per thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Enter a span named `name`, records logged on this thread carry it until
+/// the returned guard is dropped.
+pub fn span(name: impl Into<String>) -> SpanGuard {
+    enter(LogSpan {
+        name: name.into(),
+        fields: Vec::new(),
+    })
+}
+
+/// Enter a span carrying key-values, e.g. `span_with("request", &[("id", 7)])`.
+pub fn span_with(name: impl Into<String>, fields: &dyn Source) -> SpanGuard {
+    enter(LogSpan {
+        name: name.into(),
+        fields: collect_fields(fields),
+    })
+}
+
+fn enter(span: LogSpan) -> SpanGuard {
+    SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        spans.push(span);
+        SpanGuard {
+            depth: spans.len() - 1,
+            _not_send: PhantomData,
+        }
+    })
+}
+
+fn collect_fields(source: &dyn Source) -> Vec<LogField> {
+    let mut collector = FieldCollector(Vec::new());
+    // Collection itself never fails, keep whatever was visited.
+    let _ = source.visit(&mut collector);
+    collector.0
+}
+
+struct FieldCollector(Vec<LogField>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        // Keep the type of primitive values so the host can log them as such.
+        let value = if let Some(str) = value.to_borrowed_str() {
+            LogValue::Str(str.to_string())
+        } else if let Some(bool) = value.to_bool() {
+            LogValue::Bool(bool)
+        } else if let Some(int) = value.to_i64() {
+            LogValue::I64(int)
+        } else if let Some(int) = value.to_u64() {
+            LogValue::U64(int)
+        } else if let Some(float) = value.to_f64() {
+            LogValue::F64(float)
+        } else {
+            LogValue::Str(value.to_string())
+        };
+
+        self.0.push(LogField {
+            key: key.as_str().to_string(),
+            value,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records sent by the logger.
+    static CAPTURED: Mutex<Vec<LogRpcRequest>> = Mutex::new(Vec::new());
+
+    /// The logger, its buffer and its filter are global, tests take turns.
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    pub(super) fn capture(records: Vec<LogRpcRequest>) -> Vec<LogRpcRequest> {
+        CAPTURED.lock().unwrap().extend(records);
+        Vec::new()
+    }
+
+    /// Take the test lock with the logger installed, nothing buffered or
+    /// captured and the default filter.
+    fn setup() -> std::sync::MutexGuard<'static, ()> {
+        let guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        // Only the first test installs it.
+        let _ = init();
+        set_filter("info");
+        flush();
+        CAPTURED.lock().unwrap().clear();
+        guard
+    }
+
+    fn captured() -> Vec<LogRpcRequest> {
+        std::mem::take(&mut *CAPTURED.lock().unwrap())
+    }
+
+    fn field(key: &str, value: LogValue) -> LogField {
+        LogField { key: key.to_string(), value }
+    }
+
+    #[test]
+    fn level_target_and_key_values() {
+        let _guard = setup();
+        log::warn!(target: "app::net", attempt = 3, ratio = 0.5, fatal = false, peer = "10.0.0.1"; "retrying {}", "connect");
+        log::logger().flush();
+
+        let records = captured();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.level, LogLevel::Warn);
+        assert_eq!(record.target, "app::net");
+        assert_eq!(record.body, "retrying connect");
+        assert_eq!(record.module_path.as_deref(), Some(module_path!()));
+        assert_eq!(record.file.as_deref(), Some(file!()));
+        assert_eq!(record.fields, vec![
+            field("attempt", LogValue::I64(3)),
+            field("ratio", LogValue::F64(0.5)),
+            field("fatal", LogValue::Bool(false)),
+            field("peer", LogValue::Str("10.0.0.1".to_string())),
+        ]);
+        assert!(record.spans.is_empty());
+    }
+
+    #[test]
+    fn filtered_records_are_dropped() {
+        let _guard = setup();
+        log::debug!(target: "app", "hidden");
+        log::info!(target: "app", "shown");
+
+        set_filter("debug,app::db=off");
+        log::debug!(target: "app", "now shown");
+        log::error!(target: "app::db", "hidden");
+        log::logger().flush();
+
+        let bodies: Vec<String> = captured().into_iter().map(|record| record.body).collect();
+        assert_eq!(bodies, vec!["shown", "now shown"]);
+    }
+
+    #[test]
+    fn max_level_follows_filter() {
+        let _guard = setup();
+        assert_eq!(log::max_level(), LevelFilter::Info);
+
+        set_filter("warn,app::net=trace");
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+
+        set_filter("off");
+        assert_eq!(log::max_level(), LevelFilter::Off);
+    }
+
+    #[test]
+    fn records_carry_spans() {
+        let _guard = setup();
+        let outer = span("request");
+        {
+            let _inner = span_with("db", &[("table", "users")]);
+            log::info!("query");
+        }
+        log::info!("done");
+        drop(outer);
+        log::info!("idle");
+        log::logger().flush();
+
+        let spans: Vec<Vec<LogSpan>> = captured().into_iter().map(|record| record.spans).collect();
+        let request = LogSpan { name: "request".to_string(), fields: vec![] };
+        let db = LogSpan { name: "db".to_string(), fields: vec![field("table", LogValue::Str("users".to_string()))] };
+        assert_eq!(spans, vec![vec![request.clone(), db], vec![request], vec![]]);
+    }
+
+    #[test]
+    fn records_are_batched() {
+        let _guard = setup();
+        for _ in 0..BATCH_SIZE - 1 {
+            log::info!("buffered");
+        }
+        assert!(captured().is_empty());
+
+        log::info!("batch full");
+        assert_eq!(captured().len(), BATCH_SIZE);
+
+        // Errors go out at once, with the records before them.
+        log::info!("buffered");
+        log::error!("failed");
+        let bodies: Vec<String> = captured().into_iter().map(|record| record.body).collect();
+        assert_eq!(bodies, vec!["buffered", "failed"]);
+    }
+
+    #[test]
+    fn old_records_are_flushed() {
+        let _guard = setup();
+        log::info!("buffered");
+        assert!(captured().is_empty());
+
+        // The next record sends the records that waited too long along with it.
+        std::thread::sleep(BATCH_DELAY);
+        log::info!("late");
+        let bodies: Vec<String> = captured().into_iter().map(|record| record.body).collect();
+        assert_eq!(bodies, vec!["buffered", "late"]);
+    }
 }
@@ -1,21 +1,191 @@
-use std::{net::{TcpStream, SocketAddr}, os::fd::FromRawFd};
+use std::{fs::File, io::{self, Read, Write, IoSlice, IoSliceMut}, net::{self, TcpStream, SocketAddr, Ipv4Addr}, os::fd::{FromRawFd, AsRawFd, RawFd}};
 
-use libasi_interop::net::{NetError, ConnectRpcRequest, ConnectAddrs, LookupRpcRequest};
+use libasi_interop::net::{BindRpcRequest, BindAddr, ConnectRpcRequest, ConnectAddrs, LookupRpcRequest, ResolveRpcRequest, DATAGRAM_HEADER_LEN, encode_datagram_header, decode_datagram_header};
 
-use super::rpc::rpc_call;
+pub use libasi_interop::net::{NetError, AddressFamily, RecordType, DnsRecord};
+
+use crate::AsiRpcError;
+
+use super::rpc::{try_rpc_call, expect_rpc};
+
+/// A TCP socket listening for connections, bound by the a-Si host.
+pub struct TcpListener {
+    inner: net::TcpListener,
+}
+
+impl TcpListener {
+    /// Accept a new incoming connection, blocking until one is available.
+    pub fn accept(&self) -> io::Result<TcpStream> {
+        // WASI does not report peer addresses, so only the stream is returned.
+        self.inner.accept().map(|(stream, _)| stream)
+    }
+
+    /// Iterate over incoming connections.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+        std::iter::from_fn(|| Some(self.accept()))
+    }
+
+    /// Move the listener in or out of nonblocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// A UDP socket created by the a-Si host.
+pub struct UdpSocket {
+    file: File,
+}
+
+impl UdpSocket {
+    /// Send a datagram to `addr`, returning the number of payload bytes sent.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let header = encode_datagram_header(&addr);
+        let sent = (&self.file).write_vectored(&[IoSlice::new(&header), IoSlice::new(buf)])?;
+        Ok(sent.saturating_sub(DATAGRAM_HEADER_LEN))
+    }
+
+    /// Receive a datagram, returning the payload length and the sender's address.
+    ///
+    /// Payload that does not fit in `buf` is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut header = [0u8; DATAGRAM_HEADER_LEN];
+        let read = (&self.file).read_vectored(&mut [IoSliceMut::new(&mut header), IoSliceMut::new(buf)])?;
+        if read < DATAGRAM_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short datagram header"));
+        }
+
+        match decode_datagram_header(&header) {
+            Some(addr) => Ok((read - DATAGRAM_HEADER_LEN, addr)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "bad datagram header")),
+        }
+    }
+
+    /// Send a datagram to the connected peer.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, (Ipv4Addr::UNSPECIFIED, 0).into())
+    }
+
+    /// Receive a datagram from the connected peer.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(len, _)| len)
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Result of a fallible network call, the outer error reports RPC failures.
+pub type TryNetResult<T> = Result<Result<T, NetError>, AsiRpcError>;
+
+pub fn bind_tcp(addr: SocketAddr) -> Result<TcpListener, NetError> {
+    expect_rpc::<BindRpcRequest, _>(try_bind_tcp(addr))
+}
+
+pub fn try_bind_tcp(addr: SocketAddr) -> TryNetResult<TcpListener> {
+    let fd = try_rpc_call(&BindRpcRequest {
+        bind_addr: BindAddr::Tcp { addr },
+    })?;
+
+    Ok(fd.map(|fd| TcpListener {
+        inner: unsafe {
+            net::TcpListener::from_raw_fd(fd)
+        },
+    }))
+}
+
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket, NetError> {
+    expect_rpc::<BindRpcRequest, _>(try_bind_udp(addr))
+}
+
+pub fn try_bind_udp(addr: SocketAddr) -> TryNetResult<UdpSocket> {
+    let fd = try_rpc_call(&BindRpcRequest {
+        bind_addr: BindAddr::Udp { addr },
+    })?;
+
+    Ok(fd.map(|fd| UdpSocket {
+        file: unsafe {
+            File::from_raw_fd(fd)
+        },
+    }))
+}
 
 pub fn connect_tcp(addrs: &[SocketAddr]) -> Result<TcpStream, NetError> {
-    let fd = rpc_call(&ConnectRpcRequest {
+    expect_rpc::<ConnectRpcRequest, _>(try_connect_tcp(addrs))
+}
+
+pub fn try_connect_tcp(addrs: &[SocketAddr]) -> TryNetResult<TcpStream> {
+    let fd = try_rpc_call(&ConnectRpcRequest {
         target: ConnectAddrs::Tcp { addrs: addrs.to_vec() },
     })?;
 
-    Ok(unsafe {
+    Ok(fd.map(|fd| unsafe {
         TcpStream::from_raw_fd(fd)
-    })
+    }))
+}
+
+pub fn connect_udp(addrs: &[SocketAddr]) -> Result<UdpSocket, NetError> {
+    expect_rpc::<ConnectRpcRequest, _>(try_connect_udp(addrs))
+}
+
+pub fn try_connect_udp(addrs: &[SocketAddr]) -> TryNetResult<UdpSocket> {
+    let fd = try_rpc_call(&ConnectRpcRequest {
+        target: ConnectAddrs::Udp { addrs: addrs.to_vec() },
+    })?;
+
+    Ok(fd.map(|fd| UdpSocket {
+        file: unsafe {
+            File::from_raw_fd(fd)
+        },
+    }))
 }
 
 pub fn lookup(query: &str) -> Result<Vec<SocketAddr>, NetError> {
-    rpc_call(&LookupRpcRequest {
+    expect_rpc::<LookupRpcRequest, _>(try_lookup(query))
+}
+
+pub fn try_lookup(query: &str) -> TryNetResult<Vec<SocketAddr>> {
+    try_rpc_call(&LookupRpcRequest {
         query: query.to_string(),
     })
 }
+
+/// Resolve records of `record_type` for `hostname`.
+///
+/// Address records carry `port`, or 0 if no port is given. Names that do not
+/// exist are reported as `NetError::NotFound`.
+pub fn resolve(hostname: &str, port: Option<u16>, family: AddressFamily, record_type: RecordType) -> Result<Vec<DnsRecord>, NetError> {
+    expect_rpc::<ResolveRpcRequest, _>(try_resolve(hostname, port, family, record_type))
+}
+
+pub fn try_resolve(hostname: &str, port: Option<u16>, family: AddressFamily, record_type: RecordType) -> TryNetResult<Vec<DnsRecord>> {
+    try_rpc_call(&ResolveRpcRequest {
+        hostname: hostname.to_string(),
+        port,
+        family,
+        record_type,
+    })
+}
+
+/// Resolve the A and AAAA records for `hostname` as socket addresses.
+pub fn resolve_addrs(hostname: &str, port: u16, family: AddressFamily) -> Result<Vec<SocketAddr>, NetError> {
+    expect_rpc::<ResolveRpcRequest, _>(try_resolve_addrs(hostname, port, family))
+}
+
+pub fn try_resolve_addrs(hostname: &str, port: u16, family: AddressFamily) -> TryNetResult<Vec<SocketAddr>> {
+    let records = try_resolve(hostname, Some(port), family, RecordType::Address)?;
+    Ok(records.map(|records| records.into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::Addr(addr) => Some(addr),
+            _ => None,
+        })
+        .collect()))
+}
@@ -1,89 +1,340 @@
-use std::{fs::File, os::fd::{FromRawFd, RawFd}, io::{Write, Read, Cursor}, mem::size_of, cell::RefCell};
+use std::{fs::File, os::fd::{FromRawFd, RawFd}, io::{self, Write, Read}, cell::RefCell, collections::{HashMap, HashSet}, marker::PhantomData};
 
-use libasi_interop::{RpcRequest, AsiRpcError};
+use libasi_interop::{RpcRequest, AsiRpcError, HandshakeRpcRequest, HandshakeResponse, RPC_PROTOCOL_VERSION, encoding::{RpcEncoding, RPC_FLAG_PIPELINED, RPC_FRAME_HEADER_LEN, encode_header, decode_frame_header}};
 
 struct AsiRpcGuestDevice {
     file: File,
     poisoned: bool,
+    encoding: RpcEncoding,
+    host: Option<HandshakeResponse>,
+    /// Whether the host accepts pipelined requests.
+    pipelined: bool,
+    next_request_id: u32,
+    /// Responses read from the device but not yet collected, by request id.
+    completed: HashMap<u32, Vec<u8>>,
+    /// Requests whose caller is no longer waiting, their responses are discarded.
+    abandoned: HashSet<u32>,
 }
 
 impl AsiRpcGuestDevice {
-    pub unsafe fn new_from_root() -> Self {
+    pub unsafe fn new_from_root() -> Result<Self, AsiRpcError> {
         let fd_str = match std::env::var("ASI_RPCROOT_FD") {
             Ok(str) => str,
-            Err(_) => panic!("not running in an a-Si environment"),
+            Err(_) => return Err(AsiRpcError::Unavailable),
         };
 
         let fd: RawFd = fd_str.parse().unwrap_or(-1);
         if fd < 0 {
-            panic!("invalid a-Si RPC root device descriptor");
+            // Invalid a-Si RPC root device descriptor.
+            return Err(AsiRpcError::BadDescriptor);
         }
 
-        Self::new_from_fd(fd)
+        let mut device = Self::new_from_fd(fd);
+        device.handshake();
+        Ok(device)
     }
 
     pub unsafe fn new_from_fd(fd: RawFd) -> Self {
         Self {
             file: File::from_raw_fd(fd),
             poisoned: false,
+            encoding: RpcEncoding::Json,
+            host: None,
+            pipelined: false,
+            next_request_id: 0,
+            completed: HashMap::new(),
+            abandoned: HashSet::new(),
+        }
+    }
+
+    /// Exchange protocol versions with the host and pick the request encoding.
+    ///
+    /// Hosts that predate the handshake reject it, in which case the device
+    /// stays on JSON and nothing is known about the host.
+    pub fn handshake(&mut self) {
+        // Requests are binary encoded unless JSON is asked for to ease debugging.
+        let preferred = match std::env::var("ASI_RPC_ENCODING") {
+            Ok(encoding) if encoding.eq_ignore_ascii_case("json") => RpcEncoding::Json,
+            _ => RpcEncoding::Postcard,
+        };
+
+        self.encoding = RpcEncoding::Json;
+        self.pipelined = false;
+        if let Ok(host) = self.call(&HandshakeRpcRequest { version: RPC_PROTOCOL_VERSION }) {
+            if host.encodings.contains(&preferred.to_flags()) {
+                self.encoding = preferred;
+            }
+            self.pipelined = host.version >= 2;
+            self.host = Some(host);
+        }
+    }
+
+    /// Whether the host reported support for `op_code` during the handshake.
+    pub fn host_supports(&self, op_code: u32) -> bool {
+        match &self.host {
+            Some(host) => host.op_codes.contains(&op_code),
+            None => false,
         }
     }
 
+    /// Protocol version reported by the host, if the handshake succeeded.
+    pub fn host_version(&self) -> Option<u32> {
+        self.host.as_ref().map(|host| host.version)
+    }
+
     pub fn call<T: RpcRequest> (&mut self, request: &T) -> Result<T::Response, AsiRpcError> {
+        let request_id = self.submit(request)?;
+        self.wait::<T>(request_id)
+    }
+
+    /// Send `request` without waiting for its response, returning the id to
+    /// collect it with [`Self::wait`].
+    ///
+    /// On hosts that cannot pipeline, the call completes before returning.
+    pub fn submit<T: RpcRequest> (&mut self, request: &T) -> Result<u32, AsiRpcError> {
         if self.poisoned {
             return Err(AsiRpcError::BadDescriptor);
         }
 
-        let mut req_buffer = Cursor::new(T::OP_CODE.to_le_bytes().to_vec());
-        req_buffer.set_position(size_of::<u32>() as u64);
-        if serde_json::to_writer(&mut req_buffer, request).is_err() {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        if self.pipelined {
+            self.write_request(request, Some(request_id))?;
+        } else {
+            self.write_request(request, None)?;
+
+            let mut resp_buffer = Vec::new();
+            if self.file.read_to_end(&mut resp_buffer).is_err() {
+                // An error was encountered when reading the response, the device is in an unknown state.
+                self.poisoned = true;
+                return Err(AsiRpcError::BadDescriptor);
+            }
+            self.completed.insert(request_id, resp_buffer);
+        }
+
+        Ok(request_id)
+    }
+
+    /// Wait for the response to the request submitted as `request_id`.
+    ///
+    /// Responses to other requests that arrive first are kept for their callers.
+    pub fn wait<T: RpcRequest> (&mut self, request_id: u32) -> Result<T::Response, AsiRpcError> {
+        loop {
+            if let Some(resp_buffer) = self.completed.remove(&request_id) {
+                return match self.encoding.decode(&resp_buffer) {
+                    Ok(response) => response,
+                    Err(_) => Err(AsiRpcError::BadResponse),
+                };
+            }
+
+            if self.poisoned {
+                return Err(AsiRpcError::BadDescriptor);
+            }
+
+            match self.read_frame()? {
+                Some((id, _)) if self.abandoned.remove(&id) => (),
+                Some((id, resp_buffer)) => {
+                    self.completed.insert(id, resp_buffer);
+                },
+                None => {
+                    // Host has no response queued, the request is not outstanding.
+                    return Err(AsiRpcError::BadResponse);
+                },
+            }
+        }
+    }
+
+    /// Discard the response to `request_id` whenever it arrives.
+    pub fn abandon(&mut self, request_id: u32) {
+        if self.completed.remove(&request_id).is_none() && self.pipelined {
+            self.abandoned.insert(request_id);
+        }
+    }
+
+    fn write_request<T: RpcRequest> (&mut self, request: &T, request_id: Option<u32>) -> Result<(), AsiRpcError> {
+        let mut flags = self.encoding.to_flags();
+        if request_id.is_some() {
+            flags |= RPC_FLAG_PIPELINED;
+        }
+
+        let mut req_buffer = encode_header(T::OP_CODE, flags).to_vec();
+        if let Some(request_id) = request_id {
+            req_buffer.extend_from_slice(&request_id.to_le_bytes());
+        }
+        if self.encoding.encode_into(request, &mut req_buffer).is_err() {
             return Err(AsiRpcError::BadRequest);
         }
-        let req_buffer = req_buffer.into_inner();
 
-        match self.file.write(&req_buffer) {
-            Ok(sz) => {
-                if req_buffer.len() != sz {
-                    // Device must accept the entire write, the device is in an unknown state.
+        loop {
+            match self.file.write(&req_buffer) {
+                Ok(sz) => {
+                    if req_buffer.len() != sz {
+                        // Device must accept the entire write, the device is in an unknown state.
+                        self.poisoned = true;
+                        return Err(AsiRpcError::BadDescriptor);
+                    }
+                    return Ok(());
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && request_id.is_some() => {
+                    // The host holds too many unread responses, collect them to make room.
+                    if self.collect_frames()? == 0 {
+                        self.poisoned = true;
+                        return Err(AsiRpcError::BadDescriptor);
+                    }
+                },
+                Err(_) => {
+                    // Device was not ready, the device is in an unknown state.
                     self.poisoned = true;
                     return Err(AsiRpcError::BadDescriptor);
-                }
-            },
-            Err(_) => {
-                // Device was not ready, the device is in an unknown state.
-                self.poisoned = true;
-                return Err(AsiRpcError::BadDescriptor);
-            },
+                },
+            }
         }
-        drop(req_buffer);
+    }
 
-        let mut resp_buffer = Vec::new();
-        if let Err(_) = self.file.read_to_end(&mut resp_buffer) {
-            // An error was encountered when reading the response, the device is in an unknown state.
+    /// Read every queued response frame, keeping them for their callers. Returns
+    /// the number of frames read.
+    fn collect_frames(&mut self) -> Result<usize, AsiRpcError> {
+        let mut collected = 0;
+        while let Some((id, resp_buffer)) = self.read_frame()? {
+            collected += 1;
+            if !self.abandoned.remove(&id) {
+                self.completed.insert(id, resp_buffer);
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Read the next pipelined response frame, `None` if the host has none queued.
+    ///
+    /// The host runs pipelined requests concurrently, a read waits for the next
+    /// one to complete while any are running.
+    fn read_frame(&mut self) -> Result<Option<(u32, Vec<u8>)>, AsiRpcError> {
+        let mut header = [0u8; RPC_FRAME_HEADER_LEN];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.file.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => break,
+                Ok(sz) => filled += sz,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+
+        if filled < header.len() {
+            // Truncated frame header, the device is in an unknown state.
             self.poisoned = true;
             return Err(AsiRpcError::BadDescriptor);
         }
 
-        match serde_json::from_slice(&resp_buffer) {
-            Ok(response) => response,
-            Err(_) => Err(AsiRpcError::BadResponse),
+        let (request_id, len) = decode_frame_header(&header);
+        let mut resp_buffer = vec![0u8; len as usize];
+        if self.file.read_exact(&mut resp_buffer).is_err() {
+            // Truncated frame, the device is in an unknown state.
+            self.poisoned = true;
+            return Err(AsiRpcError::BadDescriptor);
         }
-    }
 
+        Ok(Some((request_id, resp_buffer)))
+    }
 }
 
 thread_local!(
-    static THREAD_RPC_DEVICE: RefCell<AsiRpcGuestDevice> = unsafe {
+    static THREAD_RPC_DEVICE: RefCell<Result<AsiRpcGuestDevice, AsiRpcError>> = unsafe {
         RefCell::new(AsiRpcGuestDevice::new_from_root())
     }
 );
 
+/// Whether this thread has a usable a-Si RPC device.
+pub(super) fn is_available() -> bool {
+    THREAD_RPC_DEVICE.with(|rpc_dev| rpc_dev.borrow().is_ok())
+}
+
+pub(super) fn try_rpc_call<T: RpcRequest> (request: &T) -> Result<T::Response, AsiRpcError> {
+    THREAD_RPC_DEVICE.with(|rpc_dev| {
+        match rpc_dev.borrow_mut().as_mut() {
+            Ok(device) => device.call(request),
+            Err(err) => Err(*err),
+        }
+    })
+}
+
+pub(super) fn try_rpc_submit<T: RpcRequest> (request: &T) -> Result<PendingCall<T>, AsiRpcError> {
+    THREAD_RPC_DEVICE.with(|rpc_dev| {
+        match rpc_dev.borrow_mut().as_mut() {
+            Ok(device) => device.submit(request).map(|request_id| PendingCall {
+                request_id,
+                _request: PhantomData,
+                _not_send: PhantomData,
+            }),
+            Err(err) => Err(*err),
+        }
+    })
+}
+
 pub(super) fn rpc_call<T: RpcRequest> (request: &T) -> T::Response {
-    let response_result = THREAD_RPC_DEVICE.with(|rpc_dev| {
-        rpc_dev.borrow_mut().call(request)
-    });
-    match response_result {
+    expect_rpc::<T, _>(try_rpc_call(request))
+}
+
+/// Unwrap the result of an RPC call, panicking on failure.
+pub(super) fn expect_rpc<T: RpcRequest, R> (result: Result<R, AsiRpcError>) -> R {
+    match result {
         Ok(response) => response,
         Err(err) => panic!("a-Si RPC call ({}) failed: {}", T::OP_CODE, err),
     }
 }
+
+pub(super) fn host_supports(op_code: u32) -> bool {
+    THREAD_RPC_DEVICE.with(|rpc_dev| {
+        match rpc_dev.borrow().as_ref() {
+            Ok(device) => device.host_supports(op_code),
+            Err(_) => false,
+        }
+    })
+}
+
+pub(super) fn host_version() -> Option<u32> {
+    THREAD_RPC_DEVICE.with(|rpc_dev| {
+        match rpc_dev.borrow().as_ref() {
+            Ok(device) => device.host_version(),
+            Err(_) => None,
+        }
+    })
+}
+
+/// An RPC request sent to the host whose response has not been collected yet.
+///
+/// Responses are tied to the sending thread's RPC device, so a pending call
+/// cannot leave its thread. Dropping it discards the response.
+#[must_use = "the response is discarded unless waited for"]
+pub struct PendingCall<T: RpcRequest> {
+    request_id: u32,
+    _request: PhantomData<fn() -> T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: RpcRequest> PendingCall<T> {
+    /// Block until the host has responded.
+    pub fn wait(self) -> Result<T::Response, AsiRpcError> {
+        let request_id = self.request_id;
+        std::mem::forget(self);
+
+        THREAD_RPC_DEVICE.with(|rpc_dev| {
+            match rpc_dev.borrow_mut().as_mut() {
+                Ok(device) => device.wait::<T>(request_id),
+                Err(err) => Err(*err),
+            }
+        })
+    }
+}
+
+impl<T: RpcRequest> Drop for PendingCall<T> {
+    fn drop(&mut self) {
+        THREAD_RPC_DEVICE.with(|rpc_dev| {
+            if let Ok(device) = rpc_dev.borrow_mut().as_mut() {
+                device.abandon(self.request_id);
+            }
+        })
+    }
+}